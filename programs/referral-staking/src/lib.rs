@@ -1,6 +1,6 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer};
-use anchor_lang::solana_program::program_option::COption;
+use anchor_spl::token::{self, CloseAccount, Token, TokenAccount, Transfer};
+use anchor_lang::Discriminator;
 
 declare_id!("EnGhdovdYhHk4nsHEJr6gmV5cYfrx53ky19RD56eRRGm");
 
@@ -8,13 +8,48 @@ declare_id!("EnGhdovdYhHk4nsHEJr6gmV5cYfrx53ky19RD56eRRGm");
 /// Taken from originally deployed token
 pub const HATM_TOKEN_MINT: &str = "59TF7G5NqMdqjHvpsBPojuhvksHiHVUkaNkaiVvozDrk";
 
+/// Pause flags for `GlobalState.paused`, one bit per operation
+pub const PAUSE_STAKE: u8 = 1 << 0;
+pub const PAUSE_UNSTAKE: u8 = 1 << 1;
+pub const PAUSE_CLAIM: u8 = 1 << 2;
+pub const PAUSE_COMPOUND: u8 = 1 << 3;
+pub const PAUSE_REGISTER: u8 = 1 << 4;
+pub const PAUSE_ALL: u8 = PAUSE_STAKE
+    | PAUSE_UNSTAKE
+    | PAUSE_CLAIM
+    | PAUSE_COMPOUND
+    | PAUSE_REGISTER;
+
+/// Scale of the cumulative reward index: basis points times seconds per day
+pub const REWARD_INDEX_SCALE: u128 = 10_000 * 86_400;
+
+/// Default delay between queueing and applying a parameter change (24 hours)
+pub const DEFAULT_PARAMETER_CHANGE_DELAY: i64 = 86400;
+
+/// Shortest timelock a parameter change can set (1 hour), so one change can't disable it
+pub const MIN_PARAMETER_CHANGE_DELAY: i64 = 3600;
+
+/// Reward streams a pool can run at once, each in its own slot
+pub const MAX_REWARD_STREAMS: usize = 2;
+
+/// Scale of a reward stream's per-token index
+pub const STREAM_INDEX_SCALE: u128 = 1_000_000_000_000;
+
+/// Time after a stream ends during which users can still claim from it (30 days)
+pub const REWARD_STREAM_CLAIM_WINDOW: i64 = 30 * 86400;
+
+/// Time after a campaign ends before its funder can withdraw the undistributed budget (7 days)
+pub const CAMPAIGN_GRACE_PERIOD: i64 = 7 * 86400;
+
 #[program]
 pub mod referral_staking {
     use super::*;
 
-    /// Initialize the staking vault and global state
+    /// Initialize a staking pool for a token mint
+    /// Several pools can share a mint by using different pool ids
     pub fn initialize(
         ctx: Context<Initialize>, 
+        pool_id: u64,  // Distinguishes pools that stake the same token
         reward_rate: u64,  // Daily reward rate in basis points (1/100 of a percent)
         unlock_duration: i64,  // Staking lock duration in seconds
         early_unstake_penalty: u64,  // Penalty for early unstaking in basis points
@@ -22,9 +57,33 @@ pub mod referral_staking {
         referral_reward_rate: u64,  // Reward rate for referrers in basis points
     ) -> Result<()> {
         let global_state = &mut ctx.accounts.global_state;
+        global_state.version = GlobalState::VERSION;
         global_state.authority = ctx.accounts.authority.key();
         global_state.token_mint = ctx.accounts.token_mint.key();
+        global_state.pool_id = pool_id;
         global_state.vault = ctx.accounts.vault.key();
+        
+        // Rewards come from the staking vault unless a separate reward mint is configured
+        match &ctx.accounts.reward_vault {
+            Some(reward_vault) => {
+                require_keys_neq!(
+                    ctx.accounts.reward_mint.key(),
+                    global_state.token_mint,
+                    StakingError::InvalidMint
+                );
+                global_state.reward_mint = ctx.accounts.reward_mint.key();
+                global_state.reward_vault = reward_vault.key();
+            }
+            None => {
+                require_keys_eq!(
+                    ctx.accounts.reward_mint.key(),
+                    global_state.token_mint,
+                    StakingError::InvalidMint
+                );
+                global_state.reward_mint = global_state.token_mint;
+                global_state.reward_vault = global_state.vault;
+            }
+        }
         global_state.reward_rate = reward_rate;
         global_state.unlock_duration = unlock_duration;
         global_state.early_unstake_penalty = early_unstake_penalty;
@@ -34,34 +93,100 @@ pub mod referral_staking {
         global_state.stakers_count = 0;
         global_state.reward_pool = 0;
         global_state.last_update_time = Clock::get()?.unix_timestamp;
-        // Hard-code the bump value for now since we can't access it through bumps
-        // In a proper implementation, this would be derived during account creation
-        global_state.bump = 255; // Default high bump value that will likely work
+        global_state.parameter_change_delay = DEFAULT_PARAMETER_CHANGE_DELAY;
+        global_state.reward_index = 0;
+        global_state.reward_index_updated_at = global_state.last_update_time;
+        global_state.bump = *ctx.bumps.get("global_state").unwrap();
+        
+        emit!(Initialized {
+            pool: global_state.key(),
+            authority: global_state.authority,
+            token_mint: global_state.token_mint,
+            pool_id,
+            vault: global_state.vault,
+            reward_mint: global_state.reward_mint,
+            timestamp: global_state.last_update_time,
+        });
         
         Ok(())
     }
     
     /// Register a new user in the system
     pub fn register_user(ctx: Context<RegisterUser>, referrer: Option<Pubkey>) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_REGISTER), StakingError::Paused);
+        
+        register(
+            &ctx.accounts.global_state,
+            &mut ctx.accounts.user_info,
+            ctx.accounts.owner.key(),
+            ctx.accounts.payer.key(),
+            referrer,
+            Clock::get()?.unix_timestamp,
+        )
+    }
+    
+    /// Register the user if they have no account yet, then stake, in one instruction
+    /// An existing account keeps its referrer; `referrer` must then be omitted or match it
+    pub fn register_and_stake(
+        ctx: Context<RegisterAndStake>,
+        referrer: Option<Pubkey>,
+        amount: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_STAKE), StakingError::Paused);
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        let global_state = &mut ctx.accounts.global_state;
         let user_info = &mut ctx.accounts.user_info;
-        user_info.owner = ctx.accounts.owner.key();
-        user_info.staked_amount = 0;
-        user_info.rewards = 0;
-        user_info.last_stake_time = 0;
-        user_info.last_claim_time = 0;
-        user_info.referrer = referrer;
-        user_info.referral_count = 0;
-        user_info.total_referral_rewards = 0;
         
-        // For now, just record the referrer but don't increment their count
-        // This will be handled when the user stakes tokens
-        // The referrer field is set above to the passed in parameter
+        if user_info.owner == Pubkey::default() {
+            require!(!global_state.is_paused(PAUSE_REGISTER), StakingError::Paused);
+            register(
+                global_state,
+                user_info,
+                ctx.accounts.owner.key(),
+                ctx.accounts.payer.key(),
+                referrer,
+                current_time,
+            )?;
+        } else {
+            require_keys_eq!(user_info.owner, ctx.accounts.owner.key(), StakingError::InvalidOwner);
+            require!(
+                referrer.is_none() || referrer == user_info.referrer,
+                StakingError::InvalidReferrer
+            );
+        }
+        
+        // Check minimum stake amount
+        require!(amount >= global_state.min_stake_amount, StakingError::AmountTooSmall);
+        
+        // Transfer tokens from user to vault
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_token_account.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.owner.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+        
+        deposit_stake(
+            global_state,
+            user_info,
+            ctx.accounts.referrer_info.as_mut(),
+            ctx.remaining_accounts,
+            amount,
+            true,
+            current_time,
+        )?;
         
         Ok(())
     }
     
     /// Stake tokens into the vault
     pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_STAKE), StakingError::Paused);
+        
         let global_state = &mut ctx.accounts.global_state;
         let user_info = &mut ctx.accounts.user_info;
         
@@ -79,51 +204,94 @@ pub mod referral_staking {
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
         token::transfer(cpi_ctx, amount)?;
         
-        // Calculate pending rewards before updating state
         let current_time = Clock::get()?.unix_timestamp;
-        if user_info.staked_amount > 0 && current_time > user_info.last_stake_time {
-            let time_passed = (current_time - user_info.last_stake_time) as u64;
-            let reward = calculate_reward(
-                user_info.staked_amount,
-                time_passed,
-                global_state.reward_rate,
-            );
-            user_info.rewards = user_info.rewards.checked_add(reward).unwrap_or(user_info.rewards);
-        }
+        deposit_stake(
+            global_state,
+            user_info,
+            ctx.accounts.referrer_info.as_mut(),
+            ctx.remaining_accounts,
+            amount,
+            true,
+            current_time,
+        )?;
         
-        // Update user state
-        user_info.staked_amount = user_info.staked_amount.checked_add(amount).unwrap_or(user_info.staked_amount);
-        user_info.last_stake_time = current_time;
+        Ok(())
+    }
+    
+    /// Stake tokens from the payer's token account into a registered beneficiary's position
+    /// The beneficiary owns the stake and unstakes it as usual. The gift leaves the
+    /// beneficiary's own lock timer and terms untouched; with `lock`, the gifted amount
+    /// gets its own lock of the pool's unlock duration
+    pub fn stake_for(ctx: Context<StakeFor>, amount: u64, lock: bool) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_STAKE), StakingError::Paused);
         
-        // Update global state
-        global_state.total_staked = global_state.total_staked.checked_add(amount).unwrap_or(global_state.total_staked);
-        if user_info.staked_amount == amount {
-            // This is a new staker
-            global_state.stakers_count = global_state.stakers_count.checked_add(1).unwrap_or(global_state.stakers_count);
-        }
-        global_state.last_update_time = current_time;
+        let global_state = &mut ctx.accounts.global_state;
+        let user_info = &mut ctx.accounts.user_info;
+        
+        // Check minimum stake amount
+        require!(
+            amount > 0 && amount >= global_state.min_stake_amount,
+            StakingError::AmountTooSmall
+        );
+        
+        // Transfer tokens from payer to vault
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.payer_token_account.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.payer.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        deposit_stake(
+            global_state,
+            user_info,
+            ctx.accounts.referrer_info.as_mut(),
+            ctx.remaining_accounts,
+            amount,
+            false,
+            current_time,
+        )?;
         
-        // Simplified referral reward handling
-        // This will be implemented in a future version where the referrer account
-        // is properly passed as a parameter to the instruction context
-        // For now, we'll just update our local tracking
-        if user_info.staked_amount == amount && user_info.referrer.is_some() {
-            // Record that this user has a referrer in our off-chain system
-            // The actual rewards will be tracked off-chain for now
-            msg!("User has referrer: {:?}", user_info.referrer);
+        if lock {
+            // A gift joining a running gift lock unlocks with it, so gifts can't extend
+            // the lock on tokens already gifted
+            release_gift_lock(user_info, current_time);
+            if user_info.locked_amount == 0 {
+                user_info.gift_unlock_time = current_time
+                    .checked_add(global_state.unlock_duration)
+                    .ok_or(StakingError::MathOverflow)?;
+            }
+            user_info.locked_amount = user_info.locked_amount
+                .checked_add(amount)
+                .ok_or(StakingError::MathOverflow)?;
         }
         
+        emit!(StakedFor {
+            pool: global_state.key(),
+            payer: ctx.accounts.payer.key(),
+            beneficiary: user_info.owner,
+            amount,
+            locked_amount: user_info.locked_amount,
+            gift_unlock_time: user_info.gift_unlock_time,
+            timestamp: current_time,
+        });
+        
         Ok(())
     }
     
     /// Unstake tokens from the vault
     pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_UNSTAKE), StakingError::Paused);
+        
         // Store needed values first to avoid borrowing issues
         let current_time = Clock::get()?.unix_timestamp;
         let bump = ctx.accounts.global_state.bump;
-        let reward_rate = ctx.accounts.global_state.reward_rate;
-        let unlock_duration = ctx.accounts.global_state.unlock_duration;
-        let early_unstake_penalty = ctx.accounts.global_state.early_unstake_penalty;
+        let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+        let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
         
         // Now get mutable references
         let global_state = &mut ctx.accounts.global_state;
@@ -132,45 +300,56 @@ pub mod referral_staking {
         // Check if user has enough staked tokens
         require!(amount <= user_info.staked_amount, StakingError::InsufficientStakedAmount);
         
-        // Calculate pending rewards
-        if user_info.staked_amount > 0 && current_time > user_info.last_stake_time {
-            let time_passed = (current_time - user_info.last_stake_time) as u64;
-            let reward = calculate_reward(
-                user_info.staked_amount,
-                time_passed,
-                reward_rate,
-            );
-            user_info.rewards = user_info.rewards.checked_add(reward).unwrap_or(user_info.rewards);
-        }
+        // Settle pending rewards
+        settle_rewards(global_state, user_info, current_time)?;
+        settle_live_reward_streams(global_state, user_info, ctx.remaining_accounts, current_time)?;
+        
+        // Gifted stake under a gift lock stays put until the gift unlocks
+        release_gift_lock(user_info, current_time);
+        let unlocked_amount = user_info.staked_amount
+            .checked_sub(user_info.locked_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(amount <= unlocked_amount, StakingError::StakeLocked);
+        
+        // Lock terms come from the user's stake, not the current parameters
+        let unlock_duration = user_info.lock_duration;
+        let early_unstake_penalty = user_info.early_unstake_penalty;
         
         // Calculate early unstake penalty if applicable
         let mut penalty: u64 = 0;
         let time_staked = current_time - user_info.last_stake_time;
         
         if time_staked < unlock_duration {
-            penalty = (amount as u128)
-                .checked_mul(early_unstake_penalty as u128)
-                .unwrap_or(0)
-                .checked_div(10000)
-                .unwrap_or(0) as u64;
+            penalty = calculate_penalty(amount, early_unstake_penalty)?;
         }
         
-        let withdraw_amount = amount.checked_sub(penalty).unwrap_or(0);
+        let withdraw_amount = amount
+            .checked_sub(penalty)
+            .ok_or(StakingError::MathOverflow)?;
         
         // First update user state
-        user_info.staked_amount = user_info.staked_amount.checked_sub(amount).unwrap_or(0);
+        user_info.staked_amount = user_info.staked_amount
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
         user_info.last_stake_time = current_time;
         
         // Update global state
-        global_state.total_staked = global_state.total_staked.checked_sub(amount).unwrap_or(0);
-        if user_info.staked_amount == 0 {
-            // User has unstaked everything
-            global_state.stakers_count = global_state.stakers_count.checked_sub(1).unwrap_or(0);
-        }
+        global_state.total_staked = global_state.total_staked
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        update_active_staker(global_state, user_info)?;
         global_state.last_update_time = current_time;
         
-        // Add penalty to reward pool
-        global_state.reward_pool = global_state.reward_pool.checked_add(penalty).unwrap_or(global_state.reward_pool);
+        // Add penalty to reward pool, or hold it for the fee manager when rewards are another token
+        if global_state.pays_rewards_in_staked_token() {
+            global_state.reward_pool = global_state.reward_pool
+                .checked_add(penalty)
+                .ok_or(StakingError::MathOverflow)?;
+        } else {
+            global_state.collected_penalties = global_state.collected_penalties
+                .checked_add(penalty)
+                .ok_or(StakingError::MathOverflow)?;
+        }
         
         // Get a fresh reference for the global_state for transfer
         let global_state_info = ctx.accounts.global_state.to_account_info();
@@ -178,6 +357,8 @@ pub mod referral_staking {
         // Transfer tokens from vault to user
         let seeds = &[
             b"global_state".as_ref(),
+            mint_seed.as_slice(),
+            pool_id_seed.as_slice(),
             &[bump],
         ];
         let signer = &[&seeds[..]];
@@ -192,34 +373,61 @@ pub mod referral_staking {
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
         token::transfer(cpi_ctx, withdraw_amount)?;
         
+        emit!(Unstaked {
+            pool: ctx.accounts.global_state.key(),
+            owner: ctx.accounts.owner.key(),
+            amount,
+            penalty,
+            withdrawn: withdraw_amount,
+            staked_amount: ctx.accounts.user_info.staked_amount,
+            total_staked: ctx.accounts.global_state.total_staked,
+            timestamp: current_time,
+        });
+        
         Ok(())
     }
     
     /// Claim rewards
-    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+    pub fn claim_rewards<'info>(ctx: Context<'_, '_, '_, 'info, ClaimRewards<'info>>) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_CLAIM), StakingError::Paused);
+        
         // Store needed values first to avoid borrowing issues
         let current_time = Clock::get()?.unix_timestamp;
         let bump = ctx.accounts.global_state.bump;
-        let reward_rate = ctx.accounts.global_state.reward_rate;
+        let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+        let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
         
         // Now get mutable references
         let global_state = &mut ctx.accounts.global_state;
         let user_info = &mut ctx.accounts.user_info;
         
-        // Calculate pending rewards
-        if user_info.staked_amount > 0 && current_time > user_info.last_stake_time {
-            let time_passed = (current_time - user_info.last_stake_time) as u64;
-            let reward = calculate_reward(
-                user_info.staked_amount,
-                time_passed,
-                reward_rate,
-            );
-            user_info.rewards = user_info.rewards.checked_add(reward).unwrap_or(user_info.rewards);
+        // Settle pending rewards
+        settle_rewards(global_state, user_info, current_time)?;
+        
+        // Settle the reward streams being claimed, passed in remaining accounts as
+        // (stream, stream vault, user token account) triples
+        require!(
+            ctx.remaining_accounts.chunks_exact(3).remainder().is_empty(),
+            StakingError::InvalidRewardStream
+        );
+        let mut stream_claims = Vec::new();
+        for accounts in ctx.remaining_accounts.chunks(3) {
+            let stream = settle_reward_stream(global_state, user_info, &accounts[0], current_time)?;
+            require_keys_eq!(accounts[1].key(), stream.vault, StakingError::InvalidVault);
+            
+            let stream_reward = &mut user_info.stream_rewards[stream.slot as usize];
+            if stream_reward.rewards > 0 {
+                stream_claims.push((accounts, stream_reward.rewards));
+                stream_reward.rewards = 0;
+            }
         }
         
         // Check if user has rewards to claim
         let rewards_to_claim = user_info.rewards;
-        require!(rewards_to_claim > 0, StakingError::NoRewardsToClaim);
+        require!(
+            rewards_to_claim > 0 || !stream_claims.is_empty(),
+            StakingError::NoRewardsToClaim
+        );
         
         // Check if reward pool has enough tokens
         require!(
@@ -230,70 +438,171 @@ pub mod referral_staking {
         // Update user state
         user_info.rewards = 0;
         user_info.last_claim_time = current_time;
-        user_info.last_stake_time = current_time; // Reset stake time to avoid double rewards
         
         // Update global state
-        global_state.reward_pool = global_state.reward_pool.checked_sub(rewards_to_claim).unwrap_or(0);
+        global_state.reward_pool = global_state.reward_pool
+            .checked_sub(rewards_to_claim)
+            .ok_or(StakingError::MathOverflow)?;
         global_state.last_update_time = current_time;
         
         // Get a fresh reference for the global_state for transfer
         let global_state_info = ctx.accounts.global_state.to_account_info();
         
-        // Transfer rewards from vault to user
         let seeds = &[
             b"global_state".as_ref(),
+            mint_seed.as_slice(),
+            pool_id_seed.as_slice(),
             &[bump],
         ];
         let signer = &[&seeds[..]];
         
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.vault.to_account_info(),
-            to: ctx.accounts.user_token_account.to_account_info(),
-            authority: global_state_info,
-        };
+        // Transfer rewards from vault to user
+        if rewards_to_claim > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.vault.to_account_info(),
+                to: ctx.accounts.user_token_account.to_account_info(),
+                authority: global_state_info.clone(),
+            };
+            
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, rewards_to_claim)?;
+            
+            emit!(RewardsClaimed {
+                pool: ctx.accounts.global_state.key(),
+                owner: ctx.accounts.owner.key(),
+                amount: rewards_to_claim,
+                reward_pool: ctx.accounts.global_state.reward_pool,
+                timestamp: current_time,
+            });
+        }
         
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        token::transfer(cpi_ctx, rewards_to_claim)?;
+        // Transfer stream rewards from each stream vault to user
+        for (accounts, amount) in stream_claims {
+            let cpi_accounts = Transfer {
+                from: accounts[1].clone(),
+                to: accounts[2].clone(),
+                authority: global_state_info.clone(),
+            };
+            
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, amount)?;
+            
+            emit!(StreamRewardsClaimed {
+                pool: ctx.accounts.global_state.key(),
+                stream: accounts[0].key(),
+                owner: ctx.accounts.owner.key(),
+                amount,
+                timestamp: current_time,
+            });
+        }
         
         Ok(())
     }
     
     /// Compound rewards (add rewards to staked amount)
     pub fn compound_rewards(ctx: Context<CompoundRewards>) -> Result<()> {
+        require!(!ctx.accounts.global_state.is_paused(PAUSE_COMPOUND), StakingError::Paused);
+        require!(
+            ctx.accounts.global_state.pays_rewards_in_staked_token(),
+            StakingError::RewardMintMismatch
+        );
+        
         let global_state = &mut ctx.accounts.global_state;
         let user_info = &mut ctx.accounts.user_info;
         
-        // Calculate pending rewards
+        // Settle pending rewards
         let current_time = Clock::get()?.unix_timestamp;
-        if user_info.staked_amount > 0 && current_time > user_info.last_stake_time {
-            let time_passed = (current_time - user_info.last_stake_time) as u64;
-            let reward = calculate_reward(
-                user_info.staked_amount,
-                time_passed,
-                global_state.reward_rate,
-            );
-            user_info.rewards = user_info.rewards.checked_add(reward).unwrap_or(user_info.rewards);
-        }
+        settle_rewards(global_state, user_info, current_time)?;
+        settle_live_reward_streams(global_state, user_info, ctx.remaining_accounts, current_time)?;
         
         // Check if user has rewards to compound
         let rewards_to_compound = user_info.rewards;
         require!(rewards_to_compound > 0, StakingError::NoRewardsToClaim);
         
         // Update user state
-        user_info.staked_amount = user_info.staked_amount.checked_add(rewards_to_compound).unwrap_or(user_info.staked_amount);
+        user_info.staked_amount = user_info.staked_amount
+            .checked_add(rewards_to_compound)
+            .ok_or(StakingError::MathOverflow)?;
         user_info.rewards = 0;
         user_info.last_stake_time = current_time;
         
         // Update global state
-        global_state.total_staked = global_state.total_staked.checked_add(rewards_to_compound).unwrap_or(global_state.total_staked);
+        global_state.total_staked = global_state.total_staked
+            .checked_add(rewards_to_compound)
+            .ok_or(StakingError::MathOverflow)?;
+        update_active_staker(global_state, user_info)?;
         global_state.last_update_time = current_time;
         
+        emit!(Compounded {
+            pool: global_state.key(),
+            owner: user_info.owner,
+            amount: rewards_to_compound,
+            staked_amount: user_info.staked_amount,
+            total_staked: global_state.total_staked,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Close an empty user account and return its rent to whoever paid it
+    /// Rewards left in closed streams are forfeited and don't keep the account open
+    /// Users counted towards a referrer must pass the referrer's user info PDA,
+    /// which is skipped if the referrer has closed their own account
+    pub fn close_user(ctx: Context<CloseUser>) -> Result<()> {
+        let user_info = &ctx.accounts.user_info;
+        let global_state = &ctx.accounts.global_state;
+        require!(
+            user_info.staked_amount == 0 && user_info.rewards == 0,
+            StakingError::AccountNotEmpty
+        );
+        require!(
+            user_info.stream_rewards.iter().enumerate().all(|(slot, stream_reward)| {
+                stream_reward.rewards == 0 || global_state.is_stream_closed(slot, stream_reward.stream_id)
+            }),
+            StakingError::AccountNotEmpty
+        );
+        
+        if user_info.referral_counted {
+            let referrer = user_info.referrer.ok_or(StakingError::InvalidReferrer)?;
+            let referrer_info = ctx.accounts.referrer_info
+                .as_ref()
+                .ok_or(StakingError::InvalidReferrer)?;
+            let pool = ctx.accounts.global_state.key();
+            let (expected_pda, _) = UserInfo::find_pda(ctx.accounts.global_state.pool_seed(&pool), &referrer);
+            require_keys_eq!(referrer_info.key(), expected_pda, StakingError::InvalidReferrer);
+            
+            if referrer_info.owner == &crate::ID && !referrer_info.data_is_empty() {
+                let mut referrer_account = Account::<UserInfo>::try_from(referrer_info.as_ref())?;
+                // Saturate in case the referrer closed and re-registered since counting this user
+                referrer_account.referral_count = referrer_account.referral_count.saturating_sub(1);
+                referrer_account.exit(&crate::ID)?;
+            }
+        }
+        
+        emit!(UserClosed {
+            pool: ctx.accounts.global_state.key(),
+            owner: user_info.owner,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
         Ok(())
     }
     
     /// Add tokens to the reward pool
-    pub fn add_to_reward_pool(ctx: Context<AddToRewardPool>, amount: u64) -> Result<()> {
+    /// With `drip_duration`, the deposit is released into the pool linearly over that many
+    /// seconds, together with anything still dripping from earlier deposits
+    pub fn add_to_reward_pool(
+        ctx: Context<AddToRewardPool>,
+        amount: u64,
+        drip_duration: Option<i64>,
+    ) -> Result<()> {
+        if let Some(duration) = drip_duration {
+            require!(duration > 0, StakingError::InvalidParameter);
+        }
+        
         let global_state = &mut ctx.accounts.global_state;
         
         // Transfer tokens from user to vault
@@ -308,13 +617,203 @@ pub mod referral_staking {
         token::transfer(cpi_ctx, amount)?;
         
         // Update global state
-        global_state.reward_pool = global_state.reward_pool.checked_add(amount).unwrap_or(global_state.reward_pool);
-        global_state.last_update_time = Clock::get()?.unix_timestamp;
+        let current_time = Clock::get()?.unix_timestamp;
+        global_state.release_dripped_rewards(current_time)?;
+        match drip_duration {
+            Some(duration) => {
+                global_state.undistributed_rewards = global_state.undistributed_rewards
+                    .checked_add(amount)
+                    .ok_or(StakingError::MathOverflow)?;
+                let drip_end_time = current_time
+                    .checked_add(duration)
+                    .ok_or(StakingError::MathOverflow)?;
+                global_state.drip_end_time = global_state.drip_end_time.max(drip_end_time);
+            }
+            None => {
+                global_state.reward_pool = global_state.reward_pool
+                    .checked_add(amount)
+                    .ok_or(StakingError::MathOverflow)?;
+            }
+        }
+        global_state.last_update_time = current_time;
+        
+        emit!(RewardPoolFunded {
+            pool: global_state.key(),
+            funder: ctx.accounts.authority.key(),
+            amount,
+            reward_pool: global_state.reward_pool,
+            undistributed_rewards: global_state.undistributed_rewards,
+            drip_end_time: global_state.drip_end_time,
+            timestamp: global_state.last_update_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Donate tokens to the reward pool; callable by anyone
+    /// Cumulative donations are tracked per donor
+    pub fn donate_to_reward_pool(ctx: Context<DonateToRewardPool>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::AmountTooSmall);
+        
+        // Transfer tokens from donor to vault
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.donor_token_account.to_account_info(),
+            to: ctx.accounts.vault.to_account_info(),
+            authority: ctx.accounts.donor.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+        
+        // Update global state
+        let current_time = Clock::get()?.unix_timestamp;
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.release_dripped_rewards(current_time)?;
+        global_state.reward_pool = global_state.reward_pool
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        global_state.last_update_time = current_time;
+        
+        // Update donor record, created on the first donation
+        let donor_info = &mut ctx.accounts.donor_info;
+        if donor_info.donor == Pubkey::default() {
+            donor_info.pool = global_state.key();
+            donor_info.donor = ctx.accounts.donor.key();
+            donor_info.bump = *ctx.bumps.get("donor_info").unwrap();
+        }
+        donor_info.total_donated = donor_info.total_donated
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        donor_info.donation_count = donor_info.donation_count
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+        donor_info.last_donation_time = current_time;
+        
+        emit!(RewardPoolDonated {
+            pool: global_state.key(),
+            donor: donor_info.donor,
+            amount,
+            total_donated: donor_info.total_donated,
+            reward_pool: global_state.reward_pool,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Withdraw early unstake penalties held in the staking vault
+    /// Only pools paying rewards in another token collect penalties here
+    pub fn withdraw_penalties(ctx: Context<WithdrawPenalties>) -> Result<()> {
+        let amount = ctx.accounts.global_state.collected_penalties;
+        require!(amount > 0, StakingError::NoPenaltiesCollected);
+        
+        let bump = ctx.accounts.global_state.bump;
+        let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+        let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
+        
+        ctx.accounts.global_state.collected_penalties = 0;
+        
+        // Transfer penalties from vault to the fee manager's account
+        let seeds = &[
+            b"global_state".as_ref(),
+            mint_seed.as_slice(),
+            pool_id_seed.as_slice(),
+            &[bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.vault.to_account_info(),
+            to: ctx.accounts.destination.to_account_info(),
+            authority: ctx.accounts.global_state.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+        
+        emit!(PenaltiesWithdrawn {
+            pool: ctx.accounts.global_state.key(),
+            withdrawn_by: ctx.accounts.authority.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
         
         Ok(())
     }
     
-    /// Update staking parameters
+    /// Read-only view of a user's position with rewards and penalties computed as of now
+    /// Returned via return data, so `simulateTransaction` gives authoritative numbers
+    pub fn get_user_info(ctx: Context<GetUserInfo>) -> Result<UserInfoData> {
+        let current_time = Clock::get()?.unix_timestamp;
+        
+        // Settle on copies so nothing is written
+        let mut global_state = (*ctx.accounts.global_state).clone();
+        let mut user_info = (*ctx.accounts.user_info).clone();
+        settle_rewards(&mut global_state, &mut user_info, current_time)?;
+        
+        let locked_amount = if current_time < user_info.gift_unlock_time {
+            user_info.locked_amount
+        } else {
+            0
+        };
+        let unlock_time = user_info.last_stake_time.saturating_add(user_info.lock_duration);
+        let is_locked = user_info.staked_amount > 0 && current_time < unlock_time;
+        // Gift-locked stake can't be unstaked now, so it carries no penalty either
+        let penalty_if_unstaked_now = if is_locked {
+            let unstakable = user_info.staked_amount
+                .checked_sub(locked_amount)
+                .ok_or(StakingError::MathOverflow)?;
+            calculate_penalty(unstakable, user_info.early_unstake_penalty)?
+        } else {
+            0
+        };
+        
+        Ok(UserInfoData {
+            owner: user_info.owner,
+            staked_amount: user_info.staked_amount,
+            pending_rewards: user_info.rewards,
+            last_stake_time: user_info.last_stake_time,
+            last_claim_time: user_info.last_claim_time,
+            referrer: user_info.referrer,
+            referral_count: user_info.referral_count,
+            total_referral_rewards: user_info.total_referral_rewards,
+            time_until_unlock: if is_locked { Some(unlock_time - current_time) } else { None },
+            is_locked,
+            penalty_if_unstaked_now,
+            locked_amount,
+            apr_bps: calculate_apr(global_state.reward_rate),
+        })
+    }
+    
+    /// Read-only view of pool-wide figures, returned via return data
+    pub fn get_vault_info(ctx: Context<GetVaultInfo>) -> Result<VaultInfoData> {
+        // Release on a copy so nothing is written
+        let mut global_state = (*ctx.accounts.global_state).clone();
+        global_state.release_dripped_rewards(Clock::get()?.unix_timestamp)?;
+        
+        Ok(VaultInfoData {
+            token_mint: global_state.token_mint,
+            pool_id: global_state.pool_id,
+            reward_mint: global_state.reward_mint(),
+            total_staked: global_state.total_staked,
+            stakers_count: global_state.stakers_count,
+            reward_pool: global_state.reward_pool,
+            undistributed_rewards: global_state.undistributed_rewards,
+            reward_rate: global_state.reward_rate,
+            apr_bps: calculate_apr(global_state.reward_rate),
+            unlock_duration: global_state.unlock_duration,
+            early_unstake_penalty: global_state.early_unstake_penalty,
+            referral_reward_rate: global_state.referral_reward_rate,
+            min_stake_amount: global_state.min_stake_amount,
+            paused: global_state.paused,
+            emergency: global_state.emergency,
+        })
+    }
+    
+    /// Queue a staking parameter update; it takes effect after the timelock delay
     pub fn update_parameters(
         ctx: Context<UpdateParameters>,
         reward_rate: Option<u64>,
@@ -322,293 +821,3152 @@ pub mod referral_staking {
         early_unstake_penalty: Option<u64>,
         min_stake_amount: Option<u64>,
         referral_reward_rate: Option<u64>,
+        parameter_change_delay: Option<i64>,
     ) -> Result<()> {
+        // Once a council is in place, parameter changes must go through a proposal
+        require!(
+            !ctx.accounts.global_state.council_enabled,
+            StakingError::CouncilApprovalRequired
+        );
+        
+        let change = ParameterChange {
+            reward_rate,
+            unlock_duration,
+            early_unstake_penalty,
+            min_stake_amount,
+            referral_reward_rate,
+            parameter_change_delay,
+        };
+        queue_parameter_change(
+            &ctx.accounts.global_state,
+            &mut ctx.accounts.pending_change,
+            change,
+            ctx.accounts.authority.key(),
+            *ctx.bumps.get("pending_change").unwrap(),
+        )?;
+        
+        Ok(())
+    }
+    
+    /// Apply a queued parameter change once its effective time has passed
+    /// Permissionless so a crank can apply it on schedule
+    pub fn apply_pending_parameters(ctx: Context<ApplyPendingParameters>) -> Result<()> {
+        let pending_change = &ctx.accounts.pending_change;
         let global_state = &mut ctx.accounts.global_state;
         
-        // Only update parameters that are provided
-        if let Some(rate) = reward_rate {
-            global_state.reward_rate = rate;
-        }
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            current_time >= pending_change.effective_time,
+            StakingError::TimelockNotElapsed
+        );
         
-        if let Some(duration) = unlock_duration {
-            global_state.unlock_duration = duration;
-        }
+        apply_parameter_change(global_state, &pending_change.change)?;
         
-        if let Some(penalty) = early_unstake_penalty {
-            require!(penalty <= 5000, StakingError::PenaltyTooHigh); // Max 50%
-            global_state.early_unstake_penalty = penalty;
-        }
+        Ok(())
+    }
+    
+    /// Cancel a queued parameter change before it is applied
+    pub fn cancel_pending_parameters(ctx: Context<CancelPendingParameters>) -> Result<()> {
+        // Once a council is in place, only a council proposal can veto a queued change
+        require!(
+            !ctx.accounts.global_state.council_enabled,
+            StakingError::CouncilApprovalRequired
+        );
         
-        if let Some(min_amount) = min_stake_amount {
-            global_state.min_stake_amount = min_amount;
-        }
+        emit!(ParameterChangeCancelled {
+            pool: ctx.accounts.global_state.key(),
+            change: ctx.accounts.pending_change.change.clone(),
+            cancelled_at: Clock::get()?.unix_timestamp,
+        });
         
-        if let Some(referral_rate) = referral_reward_rate {
-            require!(referral_rate <= 2000, StakingError::ReferralRateTooHigh); // Max 20%
-            global_state.referral_reward_rate = referral_rate;
-        }
+        Ok(())
+    }
+    
+    /// Set up the M-of-N admin council; from then on parameter changes require its approval
+    /// Also re-enables a council that disabled itself, keeping its proposal numbering
+    pub fn initialize_council(
+        ctx: Context<InitializeCouncil>,
+        members: Vec<Pubkey>,
+        threshold: u8,
+    ) -> Result<()> {
+        require!(!ctx.accounts.global_state.council_enabled, StakingError::InvalidCouncil);
+        validate_council(&members, threshold)?;
+        
+        let council = &mut ctx.accounts.council;
+        council.members = members;
+        council.threshold = threshold;
+        // Proposals left over from before a disable can't be approved or executed
+        council.epoch = council.epoch
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+        council.bump = *ctx.bumps.get("council").unwrap();
         
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.council_enabled = true;
         global_state.last_update_time = Clock::get()?.unix_timestamp;
         
+        emit!(CouncilInitialized {
+            pool: global_state.key(),
+            members: council.members.clone(),
+            threshold: council.threshold,
+            timestamp: global_state.last_update_time,
+        });
+        
         Ok(())
     }
-}
-
-/// Calculate reward based on amount, time passed, and rate
-fn calculate_reward(amount: u64, time_passed: u64, daily_rate: u64) -> u64 {
-    let seconds_in_day: u64 = 86400;
     
-    // Calculate daily reward: amount * rate / 10000 (rate is in basis points)
-    let daily_reward = (amount as u128)
-        .checked_mul(daily_rate as u128)
-        .unwrap_or(0)
-        .checked_div(10000)
-        .unwrap_or(0);
-    
-    // Calculate reward for time passed: daily_reward * time_passed / seconds_in_day
-    let reward = daily_reward
-        .checked_mul(time_passed as u128)
-        .unwrap_or(0)
-        .checked_div(seconds_in_day as u128)
-        .unwrap_or(0);
+    /// Propose an action to the admin council; the proposer approves it implicitly
+    pub fn create_proposal(ctx: Context<CreateProposal>, action: ProposalAction) -> Result<()> {
+        action.validate()?;
+        
+        let council = &mut ctx.accounts.council;
+        let proposal = &mut ctx.accounts.proposal;
+        
+        proposal.id = council.proposal_count;
+        proposal.epoch = council.epoch;
+        proposal.proposer = ctx.accounts.proposer.key();
+        proposal.action = action;
+        proposal.approvals = vec![ctx.accounts.proposer.key()];
+        proposal.executed = false;
+        proposal.created_at = Clock::get()?.unix_timestamp;
+        proposal.bump = *ctx.bumps.get("proposal").unwrap();
+        
+        council.proposal_count = council.proposal_count
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+        
+        emit!(ProposalCreated {
+            pool: ctx.accounts.global_state.key(),
+            id: proposal.id,
+            proposer: proposal.proposer,
+            action: proposal.action.clone(),
+            timestamp: proposal.created_at,
+        });
+        
+        Ok(())
+    }
     
-    reward as u64
-}
-
-/// Calculate referral reward based on amount and rate
-fn calculate_referral_reward(amount: u64, referral_rate: u64) -> u64 {
-    (amount as u128)
-        .checked_mul(referral_rate as u128)
-        .unwrap_or(0)
-        .checked_div(10000)
-        .unwrap_or(0) as u64
-}
-
-/// User information account
-#[account]
-#[derive(Default)]
-pub struct UserInfo {
-    pub owner: Pubkey,
-    pub staked_amount: u64,
-    pub rewards: u64,
-    pub last_stake_time: i64,
-    pub last_claim_time: i64,
-    pub referrer: Option<Pubkey>,
-    pub referral_count: u64,
-    pub total_referral_rewards: u64,
-}
-
-impl UserInfo {
-    pub const LEN: usize = 8 + // discriminator
-        32 + // owner
-        8 + // staked_amount
-        8 + // rewards
-        8 + // last_stake_time
-        8 + // last_claim_time
-        33 + // referrer (Option<Pubkey>)
-        8 + // referral_count
-        8; // total_referral_rewards
+    /// Approve a pending council proposal
+    pub fn approve_proposal(ctx: Context<ApproveProposal>) -> Result<()> {
+        let member = ctx.accounts.member.key();
+        let proposal = &mut ctx.accounts.proposal;
+        
+        require!(!proposal.executed, StakingError::ProposalAlreadyExecuted);
+        require!(proposal.epoch == ctx.accounts.council.epoch, StakingError::StaleProposal);
+        require!(!proposal.approvals.contains(&member), StakingError::AlreadyApproved);
+        proposal.approvals.push(member);
+        
+        emit!(ProposalApproved {
+            pool: ctx.accounts.global_state.key(),
+            id: proposal.id,
+            member,
+            approvals: ctx.accounts.council.approvals(proposal) as u8,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
     
-    pub fn find_pda(owner: &Pubkey) -> (Pubkey, u8) {
-        Pubkey::find_program_address(
-            &[b"user_info".as_ref(), owner.as_ref()],
-            &crate::ID,
-        )
+    /// Execute a parameter change proposal that reached the approval threshold
+    /// The change is queued behind the same timelock as `update_parameters`
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        ctx.accounts.council.require_approved(proposal)?;
+        
+        let ProposalAction::UpdateParameters(change) = proposal.action.clone() else {
+            return err!(StakingError::InvalidProposalAction);
+        };
+        
+        queue_parameter_change(
+            &ctx.accounts.global_state,
+            &mut ctx.accounts.pending_change,
+            change,
+            ctx.accounts.executor.key(),
+            *ctx.bumps.get("pending_change").unwrap(),
+        )?;
+        proposal.executed = true;
+        
+        emit!(ProposalExecuted {
+            pool: ctx.accounts.global_state.key(),
+            id: proposal.id,
+            executor: ctx.accounts.executor.key(),
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
     }
-}
-
-/// Global state account
-#[account]
-pub struct GlobalState {
-    pub authority: Pubkey,
+    
+    /// Execute an approved proposal cancelling the queued parameter change
+    pub fn execute_cancel_proposal(ctx: Context<ExecuteCancelProposal>) -> Result<()> {
+        let proposal = &mut ctx.accounts.proposal;
+        ctx.accounts.council.require_approved(proposal)?;
+        require!(
+            matches!(proposal.action, ProposalAction::CancelPendingParameters),
+            StakingError::InvalidProposalAction
+        );
+        proposal.executed = true;
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        emit!(ParameterChangeCancelled {
+            pool: ctx.accounts.global_state.key(),
+            change: ctx.accounts.pending_change.change.clone(),
+            cancelled_at: current_time,
+        });
+        
+        emit!(ProposalExecuted {
+            pool: ctx.accounts.global_state.key(),
+            id: proposal.id,
+            executor: ctx.accounts.executor.key(),
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Execute an approved proposal rotating the council's members and threshold,
+    /// or disabling the council so parameter changes go back to the parameter admin
+    pub fn execute_council_proposal(ctx: Context<ExecuteCouncilProposal>) -> Result<()> {
+        let council = &mut ctx.accounts.council;
+        let proposal = &mut ctx.accounts.proposal;
+        council.require_approved(proposal)?;
+        
+        let global_state = &mut ctx.accounts.global_state;
+        match proposal.action.clone() {
+            ProposalAction::UpdateCouncil { members, threshold } => {
+                council.members = members;
+                council.threshold = threshold;
+            }
+            ProposalAction::DisableCouncil => {
+                global_state.council_enabled = false;
+            }
+            _ => return err!(StakingError::InvalidProposalAction),
+        }
+        proposal.executed = true;
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        global_state.last_update_time = current_time;
+        
+        emit!(CouncilUpdated {
+            pool: global_state.key(),
+            members: council.members.clone(),
+            threshold: council.threshold,
+            enabled: global_state.council_enabled,
+            timestamp: current_time,
+        });
+        
+        emit!(ProposalExecuted {
+            pool: global_state.key(),
+            id: proposal.id,
+            executor: ctx.accounts.executor.key(),
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Start a reward stream in a free slot, emitting `reward_rate` tokens per second
+    /// shared pro rata among stakers between `start_time` and `end_time`
+    pub fn create_reward_stream(
+        ctx: Context<CreateRewardStream>,
+        slot: u8,
+        reward_rate: u64,
+        start_time: i64,
+        end_time: i64,
+    ) -> Result<()> {
+        require!(reward_rate > 0, StakingError::InvalidParameter);
+        
+        init_reward_stream(
+            &mut ctx.accounts.global_state,
+            &mut ctx.accounts.stream,
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.stream_vault.key(),
+            slot,
+            reward_rate,
+            start_time,
+            end_time,
+            *ctx.bumps.get("stream").unwrap(),
+        )
+    }
+    
+    /// Start a campaign: deposit a fixed budget that is emitted linearly across stakers
+    /// between `start_time` and `end_time`, running as a reward stream in `slot`
+    /// Budget left undistributed can be withdrawn by the funder after the grace period
+    pub fn start_campaign(
+        ctx: Context<StartCampaign>,
+        slot: u8,
+        budget: u64,
+        start_time: i64,
+        end_time: i64,
+    ) -> Result<()> {
+        require!(budget > 0, StakingError::InvalidParameter);
+        
+        // Campaigns have no per-second rate; `accrue` emits the budget in proportion to elapsed time
+        init_reward_stream(
+            &mut ctx.accounts.global_state,
+            &mut ctx.accounts.stream,
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.stream_vault.key(),
+            slot,
+            0,
+            start_time,
+            end_time,
+            *ctx.bumps.get("stream").unwrap(),
+        )?;
+        
+        let stream = &mut ctx.accounts.stream;
+        stream.funder = ctx.accounts.authority.key();
+        stream.budget = budget;
+        
+        // Transfer the budget from the funder to the stream vault
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            to: ctx.accounts.stream_vault.to_account_info(),
+            authority: ctx.accounts.authority.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, budget)?;
+        
+        emit!(CampaignStarted {
+            pool: ctx.accounts.global_state.key(),
+            stream: ctx.accounts.stream.key(),
+            funder: ctx.accounts.authority.key(),
+            budget,
+            start_time,
+            end_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Return a finished campaign's undistributed budget to its funder
+    pub fn withdraw_campaign_leftover(ctx: Context<WithdrawCampaignLeftover>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        let total_staked = ctx.accounts.global_state.total_staked;
+        let stream = &mut ctx.accounts.stream;
+        
+        require!(stream.budget > 0, StakingError::InvalidRewardStream);
+        require!(!stream.leftover_withdrawn, StakingError::InvalidRewardStream);
+        let withdrawable_at = stream.end_time
+            .checked_add(CAMPAIGN_GRACE_PERIOD)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(current_time >= withdrawable_at, StakingError::RewardStreamActive);
+        
+        // Finalize the emission so `distributed` covers the whole campaign
+        stream.accrue(total_staked, current_time)?;
+        let leftover = stream.budget.saturating_sub(stream.distributed);
+        stream.leftover_withdrawn = true;
+        
+        if leftover > 0 {
+            let bump = ctx.accounts.global_state.bump;
+            let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+            let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
+            let seeds = &[
+                b"global_state".as_ref(),
+                mint_seed.as_slice(),
+                pool_id_seed.as_slice(),
+                &[bump],
+            ];
+            let signer = &[&seeds[..]];
+            
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.stream_vault.to_account_info(),
+                to: ctx.accounts.funder_token_account.to_account_info(),
+                authority: ctx.accounts.global_state.to_account_info(),
+            };
+            
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, leftover)?;
+        }
+        
+        emit!(CampaignLeftoverWithdrawn {
+            pool: ctx.accounts.global_state.key(),
+            stream: ctx.accounts.stream.key(),
+            funder: ctx.accounts.funder.key(),
+            distributed: ctx.accounts.stream.distributed,
+            leftover,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Deposit tokens into a reward stream's vault
+    pub fn fund_reward_stream(ctx: Context<FundRewardStream>, amount: u64) -> Result<()> {
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            to: ctx.accounts.stream_vault.to_account_info(),
+            authority: ctx.accounts.authority.to_account_info(),
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token::transfer(cpi_ctx, amount)?;
+        
+        emit!(RewardStreamFunded {
+            pool: ctx.accounts.global_state.key(),
+            stream: ctx.accounts.stream.key(),
+            funder: ctx.accounts.authority.key(),
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
+    
+    /// Close a finished reward stream once its claim window has passed, freeing its slot
+    /// Tokens left in the vault, including rewards nobody claimed, go to `destination`
+    pub fn close_reward_stream(ctx: Context<CloseRewardStream>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        let stream = &ctx.accounts.stream;
+        let claim_deadline = stream.end_time
+            .checked_add(REWARD_STREAM_CLAIM_WINDOW)
+            .ok_or(StakingError::MathOverflow)?;
+        require!(current_time >= claim_deadline, StakingError::RewardStreamActive);
+        
+        let bump = ctx.accounts.global_state.bump;
+        let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+        let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
+        let seeds = &[
+            b"global_state".as_ref(),
+            mint_seed.as_slice(),
+            pool_id_seed.as_slice(),
+            &[bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        // Sweep what is left, then close the vault
+        let remaining = ctx.accounts.stream_vault.amount;
+        if remaining > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.stream_vault.to_account_info(),
+                to: ctx.accounts.destination.to_account_info(),
+                authority: ctx.accounts.global_state.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token::transfer(cpi_ctx, remaining)?;
+        }
+        
+        let cpi_accounts = CloseAccount {
+            account: ctx.accounts.stream_vault.to_account_info(),
+            destination: ctx.accounts.authority.to_account_info(),
+            authority: ctx.accounts.global_state.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::close_account(cpi_ctx)?;
+        
+        // Rewards users still hold in this stream are forfeited from now on
+        let slot = ctx.accounts.stream.slot;
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.live_streams &= !(1 << slot);
+        global_state.closed_stream_ids[slot as usize] = ctx.accounts.stream.id;
+        
+        emit!(RewardStreamClosed {
+            pool: ctx.accounts.global_state.key(),
+            stream: ctx.accounts.stream.key(),
+            remaining,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Set the pause bitmask (see `PAUSE_*`), callable by the authority or the pauser
+    pub fn set_pause(ctx: Context<SetPause>, paused: u8) -> Result<()> {
+        require!(paused & !PAUSE_ALL == 0, StakingError::InvalidParameter);
+        
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.paused = paused;
+        global_state.last_update_time = Clock::get()?.unix_timestamp;
+        
+        emit!(PauseUpdated {
+            pool: global_state.key(),
+            paused,
+            updated_by: ctx.accounts.authority.key(),
+            timestamp: global_state.last_update_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Enable or disable emergency mode, which allows penalty-free principal withdrawal
+    /// Callable by the authority or the pauser
+    pub fn set_emergency(ctx: Context<SetEmergency>, enabled: bool) -> Result<()> {
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.emergency = enabled;
+        global_state.last_update_time = Clock::get()?.unix_timestamp;
+        
+        emit!(EmergencyModeUpdated {
+            pool: global_state.key(),
+            enabled,
+            updated_by: ctx.accounts.authority.key(),
+            timestamp: global_state.last_update_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Withdraw the full staked amount while emergency mode is on
+    /// No penalty is charged and pending rewards, including reward stream rewards, are forfeited
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        require!(ctx.accounts.global_state.emergency, StakingError::NotInEmergency);
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        let bump = ctx.accounts.global_state.bump;
+        let mint_seed = ctx.accounts.global_state.seed_mint().to_vec();
+        let pool_id_seed = ctx.accounts.global_state.seed_pool_id();
+        
+        let global_state = &mut ctx.accounts.global_state;
+        let user_info = &mut ctx.accounts.user_info;
+        
+        let amount = user_info.staked_amount;
+        require!(amount > 0, StakingError::InsufficientStakedAmount);
+        
+        // Bring older layouts up to date so the active flag is reliable
+        global_state.accrue_rewards(current_time)?;
+        user_info.upgrade(global_state)?;
+        
+        // Forfeit pending rewards and close out the position
+        // Streams aren't settled, so a missing or failing stream can't block the exit;
+        // their emission since the last update goes to the remaining stakers
+        let forfeited_rewards = user_info.rewards;
+        for stream_reward in user_info.stream_rewards.iter_mut() {
+            stream_reward.rewards = 0;
+        }
+        user_info.staked_amount = 0;
+        user_info.locked_amount = 0;
+        user_info.gift_unlock_time = 0;
+        user_info.rewards = 0;
+        user_info.reward_remainder = 0;
+        user_info.reward_index_snapshot = global_state.reward_index;
+        user_info.last_stake_time = current_time;
+        
+        global_state.total_staked = global_state.total_staked
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        update_active_staker(global_state, user_info)?;
+        global_state.last_update_time = current_time;
+        
+        // Get a fresh reference for the global_state for transfer
+        let global_state_info = ctx.accounts.global_state.to_account_info();
+        
+        // Transfer principal from vault to user
+        let seeds = &[
+            b"global_state".as_ref(),
+            mint_seed.as_slice(),
+            pool_id_seed.as_slice(),
+            &[bump],
+        ];
+        let signer = &[&seeds[..]];
+        
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.vault.to_account_info(),
+            to: ctx.accounts.user_token_account.to_account_info(),
+            authority: global_state_info,
+        };
+        
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+        
+        emit!(EmergencyWithdrawn {
+            pool: ctx.accounts.global_state.key(),
+            owner: ctx.accounts.owner.key(),
+            amount,
+            forfeited_rewards,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Assign or revoke an operational role; the root authority implicitly holds every role
+    pub fn set_role(ctx: Context<SetRole>, role: Role, holder: Option<Pubkey>) -> Result<()> {
+        let global_state = &mut ctx.accounts.global_state;
+        match role {
+            Role::Pauser => global_state.pauser = holder,
+            Role::FeeManager => global_state.fee_manager = holder,
+            Role::RewardFunder => global_state.reward_funder = holder,
+            Role::ParameterAdmin => global_state.parameter_admin = holder,
+        }
+        
+        emit!(RoleUpdated {
+            pool: global_state.key(),
+            role,
+            holder,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
+    
+    /// Propose a new authority, which only takes effect once accepted by that key
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        let global_state = &mut ctx.accounts.global_state;
+        global_state.pending_authority = Some(new_authority);
+        
+        emit!(AuthorityTransferProposed {
+            pool: global_state.key(),
+            authority: global_state.authority,
+            pending_authority: new_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
+    
+    /// Accept a pending authority transfer
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let global_state = &mut ctx.accounts.global_state;
+        let previous_authority = global_state.authority;
+        global_state.authority = ctx.accounts.new_authority.key();
+        global_state.pending_authority = None;
+        global_state.last_update_time = Clock::get()?.unix_timestamp;
+        
+        emit!(AuthorityTransferred {
+            pool: global_state.key(),
+            previous_authority,
+            authority: global_state.authority,
+            timestamp: global_state.last_update_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Cancel a pending authority transfer
+    pub fn cancel_authority_transfer(ctx: Context<CancelAuthorityTransfer>) -> Result<()> {
+        let global_state = &mut ctx.accounts.global_state;
+        let cancelled_authority = global_state.pending_authority
+            .take()
+            .ok_or(StakingError::NoPendingAuthority)?;
+        
+        emit!(AuthorityTransferCancelled {
+            pool: global_state.key(),
+            authority: global_state.authority,
+            cancelled_authority,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
+        Ok(())
+    }
+    
+    /// Migrate a user info account to the current layout
+    /// Accounts smaller than the current layout are reallocated before upgrading
+    /// Callable by the owner or by a crank; the payer covers any additional rent
+    pub fn migrate_user_info(ctx: Context<MigrateUserInfo>) -> Result<()> {
+        let user_info = ctx.accounts.user_info.to_account_info();
+        
+        let mut migrated = {
+            let data = user_info.try_borrow_data()?;
+            require!(
+                data.len() >= 8 && data[..8] == UserInfo::DISCRIMINATOR,
+                ErrorCode::AccountDiscriminatorMismatch
+            );
+            if data.len() == LegacyUserInfo::LEN {
+                UserInfo::from(LegacyUserInfo::deserialize(&mut &data[8..])?)
+            } else {
+                // Newer fields sit in zeroed space, so shorter layouts are padded with zeros
+                let mut padded = data.to_vec();
+                padded.resize(data.len().max(UserInfo::LEN), 0);
+                UserInfo::try_deserialize(&mut &padded[..])?
+            }
+        };
+        
+        require!(migrated.version < UserInfo::VERSION, StakingError::AlreadyMigrated);
+        
+        // Only the canonical PDA of the recorded owner in this pool can be migrated
+        let pool = ctx.accounts.global_state.key();
+        let (expected_pda, _) = UserInfo::find_pda(ctx.accounts.global_state.pool_seed(&pool), &migrated.owner);
+        require_keys_eq!(user_info.key(), expected_pda, StakingError::InvalidOwner);
+        
+        if user_info.data_len() < UserInfo::LEN {
+            resize_account(
+                &user_info,
+                &ctx.accounts.payer,
+                &ctx.accounts.system_program,
+                UserInfo::LEN,
+            )?;
+        }
+        
+        let global_state = &mut ctx.accounts.global_state;
+        let current_time = Clock::get()?.unix_timestamp;
+        global_state.accrue_rewards(current_time)?;
+        migrated.upgrade(global_state)?;
+        
+        let mut data = user_info.try_borrow_mut_data()?;
+        migrated.try_serialize(&mut &mut data[..])?;
+        
+        emit!(UserInfoMigrated {
+            pool: global_state.key(),
+            owner: migrated.owner,
+            version: migrated.version,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+    
+    /// Migrate the global state account to the current layout
+    /// Accounts smaller than the current layout are reallocated before upgrading
+    pub fn migrate_global_state(ctx: Context<MigrateGlobalState>) -> Result<()> {
+        let global_state = ctx.accounts.global_state.to_account_info();
+        
+        let mut migrated = {
+            let data = global_state.try_borrow_data()?;
+            require!(
+                data.len() >= 8 && data[..8] == GlobalState::DISCRIMINATOR,
+                ErrorCode::AccountDiscriminatorMismatch
+            );
+            if data.len() == LegacyGlobalState::LEN {
+                GlobalState::from(LegacyGlobalState::deserialize(&mut &data[8..])?)
+            } else {
+                // Newer fields sit in zeroed space, so shorter layouts are padded with zeros
+                let mut padded = data.to_vec();
+                padded.resize(data.len().max(GlobalState::LEN), 0);
+                GlobalState::try_deserialize(&mut &padded[..])?
+            }
+        };
+        
+        require!(migrated.version < GlobalState::VERSION, StakingError::AlreadyMigrated);
+        
+        // The pool created before pools were keyed keeps its single-pool address and seeds
+        let (legacy_pda, _) = GlobalState::find_legacy_pda();
+        migrated.legacy_seeds = global_state.key() == legacy_pda;
+        
+        // Only the canonical PDA of the recorded mint and pool id can be migrated
+        // Older layouts stored a fixed bump, so the canonical one is recorded for signing
+        let (expected_pda, bump) = migrated.find_own_pda();
+        require_keys_eq!(global_state.key(), expected_pda, StakingError::InvalidOwner);
+        migrated.bump = bump;
+        require_keys_eq!(
+            ctx.accounts.authority.key(),
+            migrated.authority,
+            StakingError::Unauthorized
+        );
+        
+        if global_state.data_len() < GlobalState::LEN {
+            resize_account(
+                &global_state,
+                &ctx.accounts.authority,
+                &ctx.accounts.system_program,
+                GlobalState::LEN,
+            )?;
+        }
+        
+        let current_time = Clock::get()?.unix_timestamp;
+        migrated.upgrade(current_time);
+        
+        let mut data = global_state.try_borrow_mut_data()?;
+        migrated.try_serialize(&mut &mut data[..])?;
+        
+        emit!(GlobalStateMigrated {
+            pool: global_state.key(),
+            version: migrated.version,
+            timestamp: current_time,
+        });
+        
+        Ok(())
+    }
+}
+
+/// Check council members are distinct and can meet the threshold
+fn validate_council(members: &[Pubkey], threshold: u8) -> Result<()> {
+    require!(
+        !members.is_empty() && members.len() <= AdminCouncil::MAX_MEMBERS,
+        StakingError::InvalidCouncil
+    );
+    require!(
+        threshold > 0 && threshold as usize <= members.len(),
+        StakingError::InvalidCouncil
+    );
+    for (i, member) in members.iter().enumerate() {
+        require!(!members[..i].contains(member), StakingError::InvalidCouncil);
+    }
+    
+    Ok(())
+}
+
+/// Validate a parameter change and record it in the pending change account
+fn queue_parameter_change(
+    global_state: &Account<GlobalState>,
+    pending_change: &mut PendingParameterChange,
+    change: ParameterChange,
+    queued_by: Pubkey,
+    bump: u8,
+) -> Result<()> {
+    change.validate()?;
+    
+    let current_time = Clock::get()?.unix_timestamp;
+    // Pools whose delay was set below the minimum still wait at least that long
+    let effective_time = current_time
+        .checked_add(global_state.parameter_change_delay.max(MIN_PARAMETER_CHANGE_DELAY))
+        .ok_or(StakingError::MathOverflow)?;
+    
+    pending_change.change = change;
+    pending_change.queued_by = queued_by;
+    pending_change.queued_at = current_time;
+    pending_change.effective_time = effective_time;
+    pending_change.bump = bump;
+    
+    emit!(ParameterChangeQueued {
+        pool: global_state.key(),
+        change: pending_change.change.clone(),
+        queued_by,
+        effective_time,
+    });
+    
+    Ok(())
+}
+
+/// Validate and apply the provided parameters, leaving the rest unchanged
+fn apply_parameter_change(
+    global_state: &mut Account<GlobalState>,
+    change: &ParameterChange,
+) -> Result<()> {
+    change.validate()?;
+    
+    // Checkpoint the reward index so time before the change is priced at the old rate
+    let current_time = Clock::get()?.unix_timestamp;
+    global_state.accrue_rewards(current_time)?;
+    
+    if let Some(rate) = change.reward_rate {
+        global_state.reward_rate = rate;
+    }
+    
+    if let Some(duration) = change.unlock_duration {
+        global_state.unlock_duration = duration;
+    }
+    
+    if let Some(penalty) = change.early_unstake_penalty {
+        global_state.early_unstake_penalty = penalty;
+    }
+    
+    if let Some(min_amount) = change.min_stake_amount {
+        global_state.min_stake_amount = min_amount;
+    }
+    
+    if let Some(referral_rate) = change.referral_reward_rate {
+        global_state.referral_reward_rate = referral_rate;
+    }
+    
+    if let Some(delay) = change.parameter_change_delay {
+        global_state.parameter_change_delay = delay;
+    }
+    
+    global_state.last_update_time = current_time;
+    
+    emit!(ParametersUpdated {
+        pool: global_state.key(),
+        reward_rate: global_state.reward_rate,
+        unlock_duration: global_state.unlock_duration,
+        early_unstake_penalty: global_state.early_unstake_penalty,
+        min_stake_amount: global_state.min_stake_amount,
+        referral_reward_rate: global_state.referral_reward_rate,
+        parameter_change_delay: global_state.parameter_change_delay,
+        timestamp: current_time,
+    });
+    
+    Ok(())
+}
+
+/// Accrue the global reward index and move the user's pending rewards into `rewards`
+fn settle_rewards(
+    global_state: &mut GlobalState,
+    user_info: &mut UserInfo,
+    current_time: i64,
+) -> Result<()> {
+    global_state.accrue_rewards(current_time)?;
+    user_info.upgrade(global_state)?;
+    
+    let index_delta = global_state.reward_index
+        .checked_sub(user_info.reward_index_snapshot)
+        .ok_or(StakingError::MathOverflow)?;
+    let (reward, remainder) = calculate_reward(
+        user_info.staked_amount,
+        index_delta,
+        user_info.reward_remainder,
+    )?;
+    
+    user_info.rewards = user_info.rewards
+        .checked_add(reward)
+        .ok_or(StakingError::MathOverflow)?;
+    user_info.reward_remainder = remainder;
+    user_info.reward_index_snapshot = global_state.reward_index;
+    
+    Ok(())
+}
+
+/// Validate and initialize a reward stream in a free slot of the pool
+#[allow(clippy::too_many_arguments)]
+fn init_reward_stream(
+    global_state: &mut Account<GlobalState>,
+    stream: &mut Account<RewardStream>,
+    mint: Pubkey,
+    vault: Pubkey,
+    slot: u8,
+    reward_rate: u64,
+    start_time: i64,
+    end_time: i64,
+    bump: u8,
+) -> Result<()> {
+    let current_time = Clock::get()?.unix_timestamp;
+    require!(
+        (slot as usize) < MAX_REWARD_STREAMS,
+        StakingError::InvalidRewardStream
+    );
+    require!(
+        start_time < end_time && end_time > current_time,
+        StakingError::InvalidParameter
+    );
+    require!(end_time.checked_sub(start_time).is_some(), StakingError::MathOverflow);
+    require!(
+        global_state.live_streams & (1 << slot) == 0,
+        StakingError::InvalidRewardStream
+    );
+    
+    global_state.stream_count = global_state.stream_count
+        .checked_add(1)
+        .ok_or(StakingError::MathOverflow)?;
+    global_state.live_streams |= 1 << slot;
+    
+    stream.pool = global_state.key();
+    stream.id = global_state.stream_count;
+    stream.slot = slot;
+    stream.mint = mint;
+    stream.vault = vault;
+    stream.reward_rate = reward_rate;
+    stream.start_time = start_time;
+    stream.end_time = end_time;
+    stream.reward_index = 0;
+    stream.last_update_time = current_time;
+    stream.bump = bump;
+    
+    emit!(RewardStreamCreated {
+        pool: stream.pool,
+        stream: stream.key(),
+        id: stream.id,
+        slot,
+        mint,
+        reward_rate,
+        start_time,
+        end_time,
+    });
+    
+    Ok(())
+}
+
+/// Accrue a reward stream passed in remaining accounts and settle the user's share of it
+/// The stream must belong to the pool; it is written back before returning
+fn settle_reward_stream(
+    global_state: &Account<GlobalState>,
+    user_info: &mut UserInfo,
+    stream_info: &AccountInfo,
+    current_time: i64,
+) -> Result<RewardStream> {
+    require_keys_eq!(*stream_info.owner, crate::ID, StakingError::InvalidRewardStream);
+    require!(stream_info.is_writable, StakingError::InvalidRewardStream);
+    
+    let mut stream = RewardStream::try_deserialize(&mut &stream_info.try_borrow_data()?[..])?;
+    require_keys_eq!(stream.pool, global_state.key(), StakingError::InvalidRewardStream);
+    
+    // Emission is shared by the stake held before this instruction changes it
+    stream.accrue(global_state.total_staked, current_time)?;
+    
+    // A slot recording an older stream means the stake hasn't changed since this one
+    // started, so the user earns from the stream's beginning; whatever is left from the
+    // older stream was forfeited when it closed
+    let stream_reward = &mut user_info.stream_rewards[stream.slot as usize];
+    if stream_reward.stream_id != stream.id {
+        require!(
+            global_state.is_stream_closed(stream.slot as usize, stream_reward.stream_id),
+            StakingError::InvalidRewardStream
+        );
+        *stream_reward = StreamReward {
+            stream_id: stream.id,
+            ..StreamReward::default()
+        };
+    }
+    
+    let index_delta = stream.reward_index
+        .checked_sub(stream_reward.reward_index_snapshot)
+        .ok_or(StakingError::MathOverflow)?;
+    let reward = (user_info.staked_amount as u128)
+        .checked_mul(index_delta)
+        .ok_or(StakingError::MathOverflow)?
+        / STREAM_INDEX_SCALE;
+    
+    stream_reward.rewards = stream_reward.rewards
+        .checked_add(u64::try_from(reward).map_err(|_| error!(StakingError::MathOverflow))?)
+        .ok_or(StakingError::MathOverflow)?;
+    stream_reward.reward_index_snapshot = stream.reward_index;
+    
+    stream.try_serialize(&mut &mut stream_info.try_borrow_mut_data()?[..])?;
+    
+    Ok(stream)
+}
+
+/// Settle every live reward stream before the user's stake or the pool's total changes
+/// Requiring all of them keeps each user's stream rewards priced at the stake actually held
+fn settle_live_reward_streams(
+    global_state: &Account<GlobalState>,
+    user_info: &mut UserInfo,
+    stream_infos: &[AccountInfo],
+    current_time: i64,
+) -> Result<()> {
+    let mut settled: u8 = 0;
+    for stream_info in stream_infos {
+        let stream = settle_reward_stream(global_state, user_info, stream_info, current_time)?;
+        settled |= 1 << stream.slot;
+    }
+    require!(
+        settled == global_state.live_streams,
+        StakingError::MissingRewardStream
+    );
+    
+    Ok(())
+}
+
+/// Initialize a new user info account, recording the referrer without crediting it
+/// The referrer's count is incremented when the user first stakes; the rent payer
+/// gets the rent back when the account is closed
+fn register(
+    global_state: &Account<GlobalState>,
+    user_info: &mut UserInfo,
+    owner: Pubkey,
+    rent_payer: Pubkey,
+    referrer: Option<Pubkey>,
+    current_time: i64,
+) -> Result<()> {
+    require!(referrer != Some(owner), StakingError::InvalidReferrer);
+    
+    user_info.version = UserInfo::VERSION;
+    user_info.owner = owner;
+    user_info.staked_amount = 0;
+    user_info.rewards = 0;
+    user_info.last_stake_time = 0;
+    user_info.last_claim_time = 0;
+    user_info.referrer = referrer;
+    user_info.referral_count = 0;
+    user_info.total_referral_rewards = 0;
+    user_info.rent_payer = rent_payer;
+    
+    emit!(Registered {
+        pool: global_state.key(),
+        owner,
+        referrer,
+        timestamp: current_time,
+    });
+    
+    Ok(())
+}
+
+/// Add tokens already transferred to the vault to a user's stake
+/// Settles rewards first and credits the referrer on the user's first stake when their
+/// account is supplied; with `restart_lock`, the position's lock restarts under the current terms
+fn deposit_stake(
+    global_state: &mut Account<GlobalState>,
+    user_info: &mut UserInfo,
+    referrer_info: Option<&mut Account<UserInfo>>,
+    remaining_accounts: &[AccountInfo],
+    amount: u64,
+    restart_lock: bool,
+    current_time: i64,
+) -> Result<()> {
+    // Settle pending rewards before updating state
+    settle_rewards(global_state, user_info, current_time)?;
+    settle_live_reward_streams(global_state, user_info, remaining_accounts, current_time)?;
+    
+    // Update user state
+    user_info.staked_amount = user_info.staked_amount
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    if restart_lock {
+        // The owner's own stake is governed by the lock terms in force now
+        user_info.last_stake_time = current_time;
+        user_info.lock_duration = global_state.unlock_duration;
+        user_info.early_unstake_penalty = global_state.early_unstake_penalty;
+    }
+    
+    // Update global state
+    global_state.total_staked = global_state.total_staked
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    update_active_staker(global_state, user_info)?;
+    global_state.last_update_time = current_time;
+    
+    // Count the user towards their referrer once the referrer's account is supplied
+    // Referral rewards are still tracked off-chain
+    if !user_info.referral_counted {
+        if let (Some(referrer), Some(referrer_info)) = (user_info.referrer, referrer_info) {
+            require_keys_eq!(referrer_info.owner, referrer, StakingError::InvalidReferrer);
+            let (expected_pda, _) = UserInfo::find_pda(global_state.pool_seed(&global_state.key()), &referrer);
+            require_keys_eq!(referrer_info.key(), expected_pda, StakingError::InvalidReferrer);
+            referrer_info.referral_count = referrer_info.referral_count
+                .checked_add(1)
+                .ok_or(StakingError::MathOverflow)?;
+            user_info.referral_counted = true;
+            
+            emit!(ReferralCredited {
+                pool: global_state.key(),
+                referrer,
+                user: user_info.owner,
+                referral_count: referrer_info.referral_count,
+                timestamp: current_time,
+            });
+        }
+    }
+    
+    emit!(Staked {
+        pool: global_state.key(),
+        owner: user_info.owner,
+        amount,
+        staked_amount: user_info.staked_amount,
+        total_staked: global_state.total_staked,
+        timestamp: current_time,
+    });
+    
+    Ok(())
+}
+
+/// Clear a gift lock once its unlock time has passed
+fn release_gift_lock(user_info: &mut UserInfo, current_time: i64) {
+    if current_time >= user_info.gift_unlock_time {
+        user_info.locked_amount = 0;
+    }
+}
+
+/// Keep `stakers_count` exact by counting only transitions of a user's active flag
+/// Call after every change to the user's staked amount
+fn update_active_staker(global_state: &mut GlobalState, user_info: &mut UserInfo) -> Result<()> {
+    let active = user_info.staked_amount > 0;
+    if active == user_info.is_active {
+        return Ok(());
+    }
+    
+    if active {
+        global_state.stakers_count = global_state.stakers_count
+            .checked_add(1)
+            .ok_or(StakingError::MathOverflow)?;
+    } else {
+        global_state.stakers_count = global_state.stakers_count
+            .checked_sub(1)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+    user_info.is_active = active;
+    
+    Ok(())
+}
+
+// Rounding rules: every division rounds in favour of the pool, so no sequence of
+// small operations can extract more than the exact amount. Rewards round down;
+// penalties round up. Overflow is always an error, never a clamped or zeroed value.
+
+/// Calculate reward for a staked amount over a reward index interval, rounding down
+/// The index advances by `daily rate (bps) * seconds`, so this is `amount * rate * time / (10000 * 86400)`
+/// The fraction lost to rounding is returned, scaled by REWARD_INDEX_SCALE, and fed back in on
+/// the next settlement so frequent settlement earns the same total as infrequent settlement
+fn calculate_reward(amount: u64, index_delta: u128, remainder: u128) -> Result<(u64, u128)> {
+    let scaled_reward = (amount as u128)
+        .checked_mul(index_delta)
+        .ok_or(StakingError::MathOverflow)?
+        .checked_add(remainder)
+        .ok_or(StakingError::MathOverflow)?;
+    
+    let reward = u64::try_from(scaled_reward / REWARD_INDEX_SCALE)
+        .map_err(|_| error!(StakingError::MathOverflow))?;
+    
+    Ok((reward, scaled_reward % REWARD_INDEX_SCALE))
+}
+
+/// Calculate early unstake penalty based on amount and penalty rate in basis points, rounding up
+/// Rounding down would let a staker dodge the penalty by unstaking in small slices
+fn calculate_penalty(amount: u64, penalty_rate: u64) -> Result<u64> {
+    let penalty = (amount as u128)
+        .checked_mul(penalty_rate as u128)
+        .ok_or(StakingError::MathOverflow)?
+        .checked_add(9999)
+        .ok_or(StakingError::MathOverflow)?
+        / 10000;
+    
+    // The penalty rate is capped at 50%, so this never exceeds the amount
+    u64::try_from(penalty).map_err(|_| error!(StakingError::MathOverflow))
+}
+
+/// Simple APR in basis points for a daily rate in basis points, without compounding
+/// Only feeds the read-only views, so an absurd rate saturates instead of failing
+fn calculate_apr(daily_rate: u64) -> u64 {
+    daily_rate.saturating_mul(365)
+}
+
+/// Grow an account to `new_len` bytes, topping up rent from the payer
+fn resize_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    new_len: usize,
+) -> Result<()> {
+    let required_lamports = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(account.lamports());
+    
+    if required_lamports > 0 {
+        let cpi_accounts = anchor_lang::system_program::Transfer {
+            from: payer.to_account_info(),
+            to: account.clone(),
+        };
+        let cpi_ctx = CpiContext::new(system_program.to_account_info(), cpi_accounts);
+        anchor_lang::system_program::transfer(cpi_ctx, required_lamports)?;
+    }
+    
+    account.realloc(new_len, true)?;
+    
+    Ok(())
+}
+
+/// User information account
+#[account]
+pub struct UserInfo {
+    pub version: u8,
+    pub owner: Pubkey,
+    pub staked_amount: u64,
+    pub rewards: u64,
+    pub last_stake_time: i64,
+    pub last_claim_time: i64,
+    pub referrer: Option<Pubkey>,
+    pub referral_count: u64,
+    pub total_referral_rewards: u64,
+    pub lock_duration: i64,  // Lock duration agreed to at stake time
+    pub early_unstake_penalty: u64,  // Early unstake penalty agreed to at stake time
+    pub reward_index_snapshot: u128,  // Global reward index at the last settlement
+    pub referral_counted: bool,  // Included in the referrer's referral_count
+    pub reward_remainder: u128,  // Reward fraction carried between settlements, scaled by REWARD_INDEX_SCALE
+    pub is_active: bool,  // Has a non-zero stake and is included in stakers_count
+    pub stream_rewards: [StreamReward; MAX_REWARD_STREAMS],  // Per reward stream slot
+    pub locked_amount: u64,  // Gifted stake that can't be unstaked before gift_unlock_time
+    pub gift_unlock_time: i64,  // Time the gift lock ends, independent of the owner's own lock
+    pub rent_payer: Pubkey,  // Paid the account rent and gets it back on close, default for the owner
+    pub reserved: [u8; UserInfo::RESERVED],  // Zeroed space for future fields
+}
+
+impl UserInfo {
+    /// Current layout version, bump whenever new fields need initialization or the layout grows
+    pub const VERSION: u8 = 5;
+    
+    /// Bytes kept free at the end of the account so new fields don't require a realloc
+    pub const RESERVED: usize = 32;
+    
+    pub const LEN: usize = 8 + // discriminator
+        1 + // version
+        32 + // owner
+        8 + // staked_amount
+        8 + // rewards
+        8 + // last_stake_time
+        8 + // last_claim_time
+        33 + // referrer (Option<Pubkey>)
+        8 + // referral_count
+        8 + // total_referral_rewards
+        8 + // lock_duration
+        8 + // early_unstake_penalty
+        16 + // reward_index_snapshot
+        1 + // referral_counted
+        16 + // reward_remainder
+        1 + // is_active
+        StreamReward::LEN * MAX_REWARD_STREAMS + // stream_rewards
+        8 + // locked_amount
+        8 + // gift_unlock_time
+        32 + // rent_payer
+        UserInfo::RESERVED; // reserved
+    
+    /// `pool_seed` is the pool's `GlobalState::pool_seed`
+    pub fn find_pda(pool_seed: &[u8], owner: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"user_info".as_ref(), pool_seed, owner.as_ref()],
+            &crate::ID,
+        )
+    }
+    
+    /// Account that receives the rent when this account is closed
+    pub fn rent_receiver(&self) -> Pubkey {
+        if self.rent_payer == Pubkey::default() {
+            self.owner
+        } else {
+            self.rent_payer
+        }
+    }
+    
+    /// Initialize fields introduced after the account's recorded version
+    /// Fields carved out of the reserved space are upgraded lazily by the handlers that read them;
+    /// the global reward index must already be accrued to `current_time`
+    pub fn upgrade(&mut self, global_state: &GlobalState) -> Result<()> {
+        if self.version < 2 {
+            // Existing stakes keep the terms in force when they were upgraded
+            self.lock_duration = global_state.unlock_duration;
+            self.early_unstake_penalty = global_state.early_unstake_penalty;
+        }
+        if self.version < 3 {
+            // Join the global index as of the last stake; the index started at the legacy
+            // rate times the pool's upgrade time, so time before then is priced at the legacy
+            // rate and later time at the rates checkpointed since
+            let legacy_index = (global_state.legacy_reward_rate as u128)
+                .checked_mul(self.last_stake_time.max(0) as u128)
+                .ok_or(StakingError::MathOverflow)?;
+            self.reward_index_snapshot = legacy_index.min(global_state.reward_index);
+        }
+        if self.version < 4 {
+            // Existing positions were already included in stakers_count
+            self.is_active = self.staked_amount > 0;
+        }
+        self.version = UserInfo::VERSION;
+        
+        Ok(())
+    }
+}
+
+/// User info layout used before account versioning was introduced
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyUserInfo {
+    pub owner: Pubkey,
+    pub staked_amount: u64,
+    pub rewards: u64,
+    pub last_stake_time: i64,
+    pub last_claim_time: i64,
+    pub referrer: Option<Pubkey>,
+    pub referral_count: u64,
+    pub total_referral_rewards: u64,
+}
+
+impl LegacyUserInfo {
+    /// Size allocated for legacy accounts, regardless of whether a referrer was set
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 33 + 8 + 8;
+}
+
+impl From<LegacyUserInfo> for UserInfo {
+    /// Carry over legacy fields as version 0; `upgrade` initializes the rest
+    fn from(legacy: LegacyUserInfo) -> Self {
+        UserInfo {
+            version: 0,
+            owner: legacy.owner,
+            staked_amount: legacy.staked_amount,
+            rewards: legacy.rewards,
+            last_stake_time: legacy.last_stake_time,
+            last_claim_time: legacy.last_claim_time,
+            referrer: legacy.referrer,
+            referral_count: legacy.referral_count,
+            total_referral_rewards: legacy.total_referral_rewards,
+            lock_duration: 0,
+            early_unstake_penalty: 0,
+            reward_index_snapshot: 0,
+            referral_counted: false,
+            reward_remainder: 0,
+            is_active: false,
+            stream_rewards: [StreamReward::default(); MAX_REWARD_STREAMS],
+            locked_amount: 0,
+            gift_unlock_time: 0,
+            rent_payer: Pubkey::default(),
+            reserved: [0; UserInfo::RESERVED],
+        }
+    }
+}
+
+/// Global state account
+#[account]
+pub struct GlobalState {
+    pub version: u8,
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub vault: Pubkey,
+    pub reward_rate: u64,  // In basis points (1/100 of a percent)
+    pub unlock_duration: i64,  // In seconds
+    pub early_unstake_penalty: u64,  // In basis points
+    pub min_stake_amount: u64,
+    pub referral_reward_rate: u64,  // In basis points
+    pub total_staked: u64,
+    pub stakers_count: u64,
+    pub reward_pool: u64,
+    pub last_update_time: i64,
+    pub bump: u8,
+    pub pending_authority: Option<Pubkey>,  // Proposed authority awaiting acceptance
+    pub council_enabled: bool,  // Parameter changes require an approved council proposal
+    pub parameter_change_delay: i64,  // Minimum timelock in seconds for parameter changes
+    pub reward_index: u128,  // Cumulative reward per token, scaled by REWARD_INDEX_SCALE
+    pub reward_index_updated_at: i64,  // Time the reward index was last accrued
+    pub paused: u8,  // Bitmask of PAUSE_* flags
+    pub pauser: Option<Pubkey>,  // Role: pause and emergency mode
+    pub emergency: bool,  // Users may withdraw principal without penalty, forfeiting rewards
+    pub fee_manager: Option<Pubkey>,  // Role: fee configuration
+    pub reward_funder: Option<Pubkey>,  // Role: reward pool funding
+    pub parameter_admin: Option<Pubkey>,  // Role: staking parameter changes
+    pub pool_id: u64,  // Distinguishes pools that stake the same token
+    pub reward_mint: Pubkey,  // Token rewards are paid in, unset on pools created before reward mints
+    pub reward_vault: Pubkey,  // Vault rewards are paid from, unset on pools created before reward mints
+    pub collected_penalties: u64,  // Penalties held in the vault when rewards are another token
+    pub stream_count: u64,  // Reward streams ever created, used as stream ids
+    pub live_streams: u8,  // Bitmask of reward stream slots in use
+    pub undistributed_rewards: u64,  // Deposits not yet released into reward_pool
+    pub drip_end_time: i64,  // Time by which undistributed_rewards is fully released
+    pub drip_updated_at: i64,  // Time undistributed_rewards was last released from
+    pub legacy_seeds: bool,  // Pool predates pool keys and keeps the single-pool seeds
+    pub legacy_reward_rate: u64,  // Rate in force when the reward index started, prices legacy users' unsettled time
+    pub closed_stream_ids: [u64; MAX_REWARD_STREAMS],  // Per slot, id of the last stream closed in it
+    pub reserved: [u8; GlobalState::RESERVED],  // Zeroed space for future fields
+}
+
+impl GlobalState {
+    /// Current layout version, bump whenever new fields need initialization
+    pub const VERSION: u8 = 6;
+    
+    /// Bytes kept free at the end of the account so new fields don't require a realloc
+    pub const RESERVED: usize = 32;
+    
+    pub const LEN: usize = 8 + // discriminator
+        1 + // version
+        32 + // authority
+        32 + // token_mint
+        32 + // vault
+        8 + // reward_rate
+        8 + // unlock_duration
+        8 + // early_unstake_penalty
+        8 + // min_stake_amount
+        8 + // referral_reward_rate
+        8 + // total_staked
+        8 + // stakers_count
+        8 + // reward_pool
+        8 + // last_update_time
+        1 + // bump
+        33 + // pending_authority (Option<Pubkey>)
+        1 + // council_enabled
+        8 + // parameter_change_delay
+        16 + // reward_index
+        8 + // reward_index_updated_at
+        1 + // paused
+        33 + // pauser (Option<Pubkey>)
+        1 + // emergency
+        33 + // fee_manager (Option<Pubkey>)
+        33 + // reward_funder (Option<Pubkey>)
+        33 + // parameter_admin (Option<Pubkey>)
+        8 + // pool_id
+        32 + // reward_mint
+        32 + // reward_vault
+        8 + // collected_penalties
+        8 + // stream_count
+        1 + // live_streams
+        8 + // undistributed_rewards
+        8 + // drip_end_time
+        8 + // drip_updated_at
+        1 + // legacy_seeds
+        8 + // legacy_reward_rate
+        8 * MAX_REWARD_STREAMS + // closed_stream_ids
+        GlobalState::RESERVED; // reserved
+    
+    pub fn find_pda(token_mint: &Pubkey, pool_id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"global_state".as_ref(), token_mint.as_ref(), &pool_id.to_le_bytes()],
+            &crate::ID,
+        )
+    }
+    
+    /// Address of the single pool created before pools were keyed by mint and pool id
+    pub fn find_legacy_pda() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"global_state".as_ref()], &crate::ID)
+    }
+    
+    /// Address of this pool, derived from its own seeds
+    pub fn find_own_pda(&self) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"global_state".as_ref(), self.seed_mint(), self.seed_pool_id().as_slice()],
+            &crate::ID,
+        )
+    }
+    
+    /// Mint seed of this pool's address; empty for the legacy pool
+    /// Empty seeds don't change a PDA, so the legacy pool keeps deriving its old address
+    pub fn seed_mint(&self) -> &[u8] {
+        if self.legacy_seeds {
+            &[]
+        } else {
+            self.token_mint.as_ref()
+        }
+    }
+    
+    /// Pool id seed of this pool's address; empty for the legacy pool
+    pub fn seed_pool_id(&self) -> Vec<u8> {
+        if self.legacy_seeds {
+            Vec::new()
+        } else {
+            self.pool_id.to_le_bytes().to_vec()
+        }
+    }
+    
+    /// Seed scoping user accounts to the pool at `pool`; empty for the legacy pool,
+    /// whose user accounts were derived without it
+    pub fn pool_seed<'a>(&self, pool: &'a Pubkey) -> &'a [u8] {
+        if self.legacy_seeds {
+            &[]
+        } else {
+            pool.as_ref()
+        }
+    }
+    
+    /// Initialize fields introduced after the account's recorded version
+    pub fn upgrade(&mut self, current_time: i64) {
+        if self.version < 2 {
+            self.parameter_change_delay = DEFAULT_PARAMETER_CHANGE_DELAY;
+        }
+        if self.version < 3 {
+            // Start the index as if the legacy rate had always applied, so each user's
+            // unsettled legacy time is priced at it when they upgrade
+            self.legacy_reward_rate = self.reward_rate;
+            self.reward_index = (self.reward_rate as u128) * (current_time.max(0) as u128);
+            self.reward_index_updated_at = current_time;
+        }
+        if self.version < 6 {
+            // Closes weren't recorded before, but every stream created so far in a free slot
+            // has been closed
+            for slot in 0..MAX_REWARD_STREAMS {
+                if self.live_streams & (1 << slot) == 0 {
+                    self.closed_stream_ids[slot] = self.stream_count;
+                }
+            }
+        }
+        self.version = GlobalState::VERSION;
+    }
+    
+    /// Whether the stream `stream_id` in `slot` has been closed, forfeiting rewards users
+    /// still hold in it; streams in a slot are closed in id order
+    pub fn is_stream_closed(&self, slot: usize, stream_id: u64) -> bool {
+        stream_id <= self.closed_stream_ids[slot]
+    }
+    
+    /// Whether `key` may act in `role`; the root authority holds every role
+    pub fn has_role(&self, role: Role, key: &Pubkey) -> bool {
+        let holder = match role {
+            Role::Pauser => self.pauser,
+            Role::FeeManager => self.fee_manager,
+            Role::RewardFunder => self.reward_funder,
+            Role::ParameterAdmin => self.parameter_admin,
+        };
+        *key == self.authority || holder == Some(*key)
+    }
+    
+    /// Token rewards are paid in; older pools pay in the staked token
+    pub fn reward_mint(&self) -> Pubkey {
+        if self.reward_mint == Pubkey::default() {
+            self.token_mint
+        } else {
+            self.reward_mint
+        }
+    }
+    
+    /// Vault rewards are paid from; older pools pay from the staking vault
+    pub fn reward_vault(&self) -> Pubkey {
+        if self.reward_vault == Pubkey::default() {
+            self.vault
+        } else {
+            self.reward_vault
+        }
+    }
+    
+    /// Whether rewards share the staking vault, so they can be compounded and fed by penalties
+    pub fn pays_rewards_in_staked_token(&self) -> bool {
+        self.reward_mint() == self.token_mint
+    }
+    
+    pub fn is_paused(&self, flag: u8) -> bool {
+        self.paused & flag != 0
+    }
+    
+    /// Move the share of `undistributed_rewards` due by `current_time` into `reward_pool`
+    /// The buffer is released linearly until `drip_end_time`
+    pub fn release_dripped_rewards(&mut self, current_time: i64) -> Result<()> {
+        if self.undistributed_rewards > 0 && current_time > self.drip_updated_at {
+            let released = if current_time >= self.drip_end_time {
+                self.undistributed_rewards
+            } else {
+                let elapsed = (current_time - self.drip_updated_at) as u128;
+                let remaining = (self.drip_end_time - self.drip_updated_at) as u128;
+                ((self.undistributed_rewards as u128)
+                    .checked_mul(elapsed)
+                    .ok_or(StakingError::MathOverflow)?
+                    / remaining) as u64
+            };
+            
+            self.undistributed_rewards -= released;
+            self.reward_pool = self.reward_pool
+                .checked_add(released)
+                .ok_or(StakingError::MathOverflow)?;
+        }
+        self.drip_updated_at = self.drip_updated_at.max(current_time);
+        
+        Ok(())
+    }
+    
+    /// Bring the cumulative reward index up to `current_time` at the current rate
+    /// Must run before `reward_rate` changes so elapsed time is priced at the old rate
+    pub fn accrue_rewards(&mut self, current_time: i64) -> Result<()> {
+        self.upgrade(current_time);
+        self.release_dripped_rewards(current_time)?;
+        
+        if current_time > self.reward_index_updated_at {
+            let time_passed = (current_time - self.reward_index_updated_at) as u128;
+            let index_delta = (self.reward_rate as u128)
+                .checked_mul(time_passed)
+                .ok_or(StakingError::MathOverflow)?;
+            self.reward_index = self.reward_index
+                .checked_add(index_delta)
+                .ok_or(StakingError::MathOverflow)?;
+            self.reward_index_updated_at = current_time;
+        }
+        
+        Ok(())
+    }
+}
+
+/// Global state layout used before account versioning was introduced
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyGlobalState {
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub vault: Pubkey,
+    pub reward_rate: u64,
+    pub unlock_duration: i64,
+    pub early_unstake_penalty: u64,
+    pub min_stake_amount: u64,
+    pub referral_reward_rate: u64,
+    pub total_staked: u64,
+    pub stakers_count: u64,
+    pub reward_pool: u64,
+    pub last_update_time: i64,
+    pub bump: u8,
+}
+
+impl LegacyGlobalState {
+    /// Size allocated for the legacy account
+    pub const LEN: usize = 8 + 32 * 3 + 8 * 9 + 1;
+}
+
+impl From<LegacyGlobalState> for GlobalState {
+    /// Carry over legacy fields as version 0; `upgrade` initializes the rest
+    fn from(legacy: LegacyGlobalState) -> Self {
+        GlobalState {
+            version: 0,
+            authority: legacy.authority,
+            token_mint: legacy.token_mint,
+            vault: legacy.vault,
+            reward_rate: legacy.reward_rate,
+            unlock_duration: legacy.unlock_duration,
+            early_unstake_penalty: legacy.early_unstake_penalty,
+            min_stake_amount: legacy.min_stake_amount,
+            referral_reward_rate: legacy.referral_reward_rate,
+            total_staked: legacy.total_staked,
+            stakers_count: legacy.stakers_count,
+            reward_pool: legacy.reward_pool,
+            last_update_time: legacy.last_update_time,
+            bump: 0,  // Legacy accounts stored a fixed bump; migration records the canonical one
+            pending_authority: None,
+            council_enabled: false,
+            parameter_change_delay: 0,
+            reward_index: 0,
+            reward_index_updated_at: 0,
+            paused: 0,
+            pauser: None,
+            emergency: false,
+            fee_manager: None,
+            reward_funder: None,
+            parameter_admin: None,
+            pool_id: 0,
+            reward_mint: Pubkey::default(),
+            reward_vault: Pubkey::default(),
+            collected_penalties: 0,
+            stream_count: 0,
+            live_streams: 0,
+            undistributed_rewards: 0,
+            drip_end_time: 0,
+            drip_updated_at: 0,
+            legacy_seeds: false,
+            legacy_reward_rate: 0,
+            closed_stream_ids: [0; MAX_REWARD_STREAMS],
+            reserved: [0; GlobalState::RESERVED],
+        }
+    }
+}
+
+/// A user's position in one reward stream slot
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct StreamReward {
+    pub stream_id: u64,  // Stream this slot was last settled against
+    pub reward_index_snapshot: u128,  // Stream reward index at the last settlement
+    pub rewards: u64,  // Settled stream rewards not yet claimed
+}
+
+impl StreamReward {
+    pub const LEN: usize = 8 + // stream_id
+        16 + // reward_index_snapshot
+        8; // rewards
+}
+
+/// User position returned by `get_user_info`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct UserInfoData {
+    pub owner: Pubkey,
+    pub staked_amount: u64,
+    pub pending_rewards: u64,  // Settled plus accrued rewards as of now
+    pub last_stake_time: i64,
+    pub last_claim_time: i64,
+    pub referrer: Option<Pubkey>,
+    pub referral_count: u64,
+    pub total_referral_rewards: u64,
+    pub time_until_unlock: Option<i64>,  // Seconds until the lock ends, None once unlocked
+    pub is_locked: bool,
+    pub penalty_if_unstaked_now: u64,  // Penalty on unstaking everything not gift-locked now
+    pub locked_amount: u64,  // Gifted stake that can't be unstaked yet, whatever the owner's own lock
+    pub apr_bps: u64,  // Simple APR in basis points, in reward tokens per staked token
+}
+
+/// Pool figures returned by `get_vault_info`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct VaultInfoData {
     pub token_mint: Pubkey,
+    pub pool_id: u64,
+    pub reward_mint: Pubkey,
+    pub total_staked: u64,
+    pub stakers_count: u64,
+    pub reward_pool: u64,
+    pub undistributed_rewards: u64,  // Deposits still dripping into the reward pool
+    pub reward_rate: u64,  // Daily rate in basis points
+    pub apr_bps: u64,  // Simple APR in basis points, without compounding
+    pub unlock_duration: i64,
+    pub early_unstake_penalty: u64,
+    pub referral_reward_rate: u64,
+    pub min_stake_amount: u64,
+    pub paused: u8,
+    pub emergency: bool,
+}
+
+/// Operational roles assignable by the root authority
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Pauser,
+    FeeManager,
+    RewardFunder,
+    ParameterAdmin,
+}
+
+/// Staking parameters to change; `None` leaves the current value in place
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ParameterChange {
+    pub reward_rate: Option<u64>,
+    pub unlock_duration: Option<i64>,
+    pub early_unstake_penalty: Option<u64>,
+    pub min_stake_amount: Option<u64>,
+    pub referral_reward_rate: Option<u64>,
+    pub parameter_change_delay: Option<i64>,
+}
+
+impl ParameterChange {
+    pub const LEN: usize = 9 + // reward_rate (Option<u64>)
+        9 + // unlock_duration (Option<i64>)
+        9 + // early_unstake_penalty (Option<u64>)
+        9 + // min_stake_amount (Option<u64>)
+        9 + // referral_reward_rate (Option<u64>)
+        9; // parameter_change_delay (Option<i64>)
+    
+    pub fn validate(&self) -> Result<()> {
+        if let Some(duration) = self.unlock_duration {
+            require!(duration >= 0, StakingError::InvalidParameter);
+        }
+        
+        if let Some(penalty) = self.early_unstake_penalty {
+            require!(penalty <= 5000, StakingError::PenaltyTooHigh); // Max 50%
+        }
+        
+        if let Some(referral_rate) = self.referral_reward_rate {
+            require!(referral_rate <= 2000, StakingError::ReferralRateTooHigh); // Max 20%
+        }
+        
+        if let Some(delay) = self.parameter_change_delay {
+            require!(delay >= MIN_PARAMETER_CHANGE_DELAY, StakingError::InvalidParameter);
+        }
+        
+        Ok(())
+    }
+}
+
+/// What a council proposal does once executed
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum ProposalAction {
+    /// Queue a parameter change behind the timelock
+    UpdateParameters(ParameterChange),
+    /// Drop the queued parameter change
+    CancelPendingParameters,
+    /// Replace the council's members and threshold
+    UpdateCouncil { members: Vec<Pubkey>, threshold: u8 },
+    /// Hand parameter changes back to the parameter admin role
+    DisableCouncil,
+}
+
+impl ProposalAction {
+    pub const LEN: usize = 1 + // variant
+        4 + 32 * AdminCouncil::MAX_MEMBERS + 1; // largest variant, UpdateCouncil
+    
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ProposalAction::UpdateParameters(change) => change.validate(),
+            ProposalAction::UpdateCouncil { members, threshold } => validate_council(members, *threshold),
+            ProposalAction::CancelPendingParameters | ProposalAction::DisableCouncil => Ok(()),
+        }
+    }
+}
+
+/// Parameter change waiting for its timelock to elapse
+#[account]
+pub struct PendingParameterChange {
+    pub change: ParameterChange,
+    pub queued_by: Pubkey,  // Receives the rent back when applied or cancelled
+    pub queued_at: i64,
+    pub effective_time: i64,  // Earliest time the change can be applied
+    pub bump: u8,
+}
+
+impl PendingParameterChange {
+    pub const LEN: usize = 8 + // discriminator
+        ParameterChange::LEN + // change
+        32 + // queued_by
+        8 + // queued_at
+        8 + // effective_time
+        1; // bump
+    
+    pub fn find_pda(pool: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"pending_parameters".as_ref(), pool.as_ref()],
+            &crate::ID,
+        )
+    }
+}
+
+/// M-of-N admin council governing parameter changes
+#[account]
+pub struct AdminCouncil {
+    pub members: Vec<Pubkey>,
+    pub threshold: u8,  // Approvals required to execute a proposal
+    pub proposal_count: u64,
+    pub epoch: u64,  // Incremented each time the council is enabled
+    pub bump: u8,
+}
+
+impl AdminCouncil {
+    pub const MAX_MEMBERS: usize = 10;
+    
+    pub const LEN: usize = 8 + // discriminator
+        4 + 32 * AdminCouncil::MAX_MEMBERS + // members
+        1 + // threshold
+        8 + // proposal_count
+        8 + // epoch
+        1; // bump
+    
+    pub fn find_pda(pool: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"admin_council".as_ref(), pool.as_ref()],
+            &crate::ID,
+        )
+    }
+    
+    pub fn is_member(&self, key: &Pubkey) -> bool {
+        self.members.contains(key)
+    }
+    
+    /// Approvals from current members; approvals by members since rotated out don't count
+    pub fn approvals(&self, proposal: &Proposal) -> usize {
+        proposal.approvals
+            .iter()
+            .filter(|member| self.is_member(member))
+            .count()
+    }
+    
+    /// Check a proposal is unexecuted, from the current epoch and approved by at least
+    /// `threshold` current members
+    pub fn require_approved(&self, proposal: &Proposal) -> Result<()> {
+        require!(!proposal.executed, StakingError::ProposalAlreadyExecuted);
+        require!(proposal.epoch == self.epoch, StakingError::StaleProposal);
+        require!(
+            self.approvals(proposal) >= self.threshold as usize,
+            StakingError::InsufficientApprovals
+        );
+        
+        Ok(())
+    }
+}
+
+/// Action awaiting council approval
+#[account]
+pub struct Proposal {
+    pub id: u64,
+    pub epoch: u64,  // Council epoch the proposal was created in
+    pub proposer: Pubkey,
+    pub action: ProposalAction,
+    pub approvals: Vec<Pubkey>,
+    pub executed: bool,
+    pub created_at: i64,
+    pub bump: u8,
+}
+
+impl Proposal {
+    pub const LEN: usize = 8 + // discriminator
+        8 + // id
+        8 + // epoch
+        32 + // proposer
+        ProposalAction::LEN + // action
+        4 + 32 * AdminCouncil::MAX_MEMBERS + // approvals
+        1 + // executed
+        8 + // created_at
+        1; // bump
+    
+    pub fn find_pda(council: &Pubkey, id: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"proposal".as_ref(), council.as_ref(), &id.to_le_bytes()],
+            &crate::ID,
+        )
+    }
+}
+
+/// Cumulative reward pool donations by one donor to one pool
+#[account]
+pub struct Donor {
+    pub pool: Pubkey,
+    pub donor: Pubkey,
+    pub total_donated: u64,
+    pub donation_count: u64,
+    pub last_donation_time: i64,
+    pub bump: u8,
+}
+
+impl Donor {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // pool
+        32 + // donor
+        8 + // total_donated
+        8 + // donation_count
+        8 + // last_donation_time
+        1; // bump
+    
+    pub fn find_pda(pool: &Pubkey, donor: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"donor".as_ref(), pool.as_ref(), donor.as_ref()],
+            &crate::ID,
+        )
+    }
+}
+
+/// Additional reward emission running alongside a pool's base rewards
+#[account]
+pub struct RewardStream {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub slot: u8,  // Index into `UserInfo.stream_rewards`
+    pub mint: Pubkey,
     pub vault: Pubkey,
-    pub reward_rate: u64,  // In basis points (1/100 of a percent)
-    pub unlock_duration: i64,  // In seconds
-    pub early_unstake_penalty: u64,  // In basis points
-    pub min_stake_amount: u64,
-    pub referral_reward_rate: u64,  // In basis points
-    pub total_staked: u64,
-    pub stakers_count: u64,
-    pub reward_pool: u64,
+    pub reward_rate: u64,  // Tokens per second shared by all stakers, zero for campaigns
+    pub start_time: i64,
+    pub end_time: i64,
+    pub reward_index: u128,  // Cumulative reward per staked token, scaled by STREAM_INDEX_SCALE
     pub last_update_time: i64,
     pub bump: u8,
+    pub distributed: u64,  // Tokens emitted to stakers so far
+    pub funder: Pubkey,  // Campaign funder, default for plain streams
+    pub budget: u64,  // Campaign budget, zero for plain streams
+    pub leftover_withdrawn: bool,  // Campaign leftover has been returned to the funder
+}
+
+impl RewardStream {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // pool
+        8 + // id
+        1 + // slot
+        32 + // mint
+        32 + // vault
+        8 + // reward_rate
+        8 + // start_time
+        8 + // end_time
+        16 + // reward_index
+        8 + // last_update_time
+        1 + // bump
+        8 + // distributed
+        32 + // funder
+        8 + // budget
+        1; // leftover_withdrawn
+    
+    pub fn find_pda(pool: &Pubkey, slot: u8) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"reward_stream".as_ref(), pool.as_ref(), &[slot]],
+            &crate::ID,
+        )
+    }
+    
+    /// Tokens emitted between the stream's start and `time`, before sharing among stakers
+    /// A campaign emits `budget * elapsed / duration`, so its whole budget is emitted by the end
+    pub fn emitted_until(&self, time: i64) -> Result<u128> {
+        let elapsed = time
+            .clamp(self.start_time, self.end_time)
+            .checked_sub(self.start_time)
+            .ok_or(StakingError::MathOverflow)? as u128;
+        
+        if self.budget > 0 {
+            let duration = self.end_time
+                .checked_sub(self.start_time)
+                .ok_or(StakingError::MathOverflow)? as u128;
+            Ok((self.budget as u128)
+                .checked_mul(elapsed)
+                .ok_or(StakingError::MathOverflow)?
+                / duration)
+        } else {
+            (self.reward_rate as u128)
+                .checked_mul(elapsed)
+                .ok_or(error!(StakingError::MathOverflow))
+        }
+    }
+    
+    /// Bring the reward index up to `current_time`, sharing emission over `total_staked`
+    /// Emission while nothing is staked is not distributed
+    pub fn accrue(&mut self, total_staked: u64, current_time: i64) -> Result<()> {
+        let from = self.last_update_time.max(self.start_time);
+        let to = current_time.min(self.end_time);
+        
+        if to > from && total_staked > 0 {
+            let emitted = self.emitted_until(to)?
+                .checked_sub(self.emitted_until(from)?)
+                .ok_or(StakingError::MathOverflow)?;
+            let index_delta = emitted
+                .checked_mul(STREAM_INDEX_SCALE)
+                .ok_or(StakingError::MathOverflow)?
+                / total_staked as u128;
+            self.reward_index = self.reward_index
+                .checked_add(index_delta)
+                .ok_or(StakingError::MathOverflow)?;
+            self.distributed = u64::try_from(emitted)
+                .ok()
+                .and_then(|emitted| self.distributed.checked_add(emitted))
+                .ok_or(StakingError::MathOverflow)?;
+        }
+        self.last_update_time = self.last_update_time.max(current_time);
+        
+        Ok(())
+    }
+}
+
+/// Initialize a staking pool
+#[derive(Accounts)]
+#[instruction(pool_id: u64)]
+pub struct Initialize<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    
+    /// Token mint to stake; its mint authority may already be revoked
+    pub token_mint: Account<'info, anchor_spl::token::Mint>,
+    
+    /// Token account that will act as the vault
+    #[account(
+        init,
+        payer = authority,
+        token::mint = token_mint,
+        token::authority = global_state,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    /// Global state account
+    #[account(
+        init,
+        payer = authority,
+        space = GlobalState::LEN,
+        seeds = [b"global_state".as_ref(), token_mint.key().as_ref(), &pool_id.to_le_bytes()],
+        bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Token rewards are paid in; the staked token unless a reward vault is supplied
+    pub reward_mint: Account<'info, anchor_spl::token::Mint>,
+    
+    /// Vault for a reward token other than the staked token, owned by the global state
+    #[account(
+        constraint = reward_vault.mint == reward_mint.key() @ StakingError::InvalidMint,
+        constraint = reward_vault.owner == global_state.key() @ StakingError::InvalidVault,
+        constraint = reward_vault.delegate.is_none() && reward_vault.close_authority.is_none() @ StakingError::InvalidVault,
+    )]
+    pub reward_vault: Option<Account<'info, TokenAccount>>,
+    
+    /// This program, used to locate its program data account
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key()) @ StakingError::Unauthorized,
+    )]
+    pub program: Program<'info, crate::program::ReferralStaking>,
+    
+    /// Program data account; only the upgrade authority can initialize
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+/// Register a new user
+#[derive(Accounts)]
+pub struct RegisterUser<'info> {
+    pub owner: Signer<'info>,
+    
+    /// Pays the user info rent; may be the owner or a sponsoring relayer
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        init,
+        payer = payer,
+        space = UserInfo::LEN,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+/// Stake tokens
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// User token account
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
+        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    /// Vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    /// Referrer's user info, credited with the referral on the user's first stake
+    #[account(
+        mut,
+        constraint = referrer_info.key() != user_info.key() @ StakingError::InvalidReferrer,
+    )]
+    pub referrer_info: Option<Account<'info, UserInfo>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Register if needed and stake tokens
+#[derive(Accounts)]
+pub struct RegisterAndStake<'info> {
+    pub owner: Signer<'info>,
+    
+    /// Pays the user info rent on first use; may be the owner or a sponsoring relayer
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account, created on the user's first call
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UserInfo::LEN,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// User token account
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
+        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    /// Vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    /// Referrer's user info, credited with the referral on the user's first stake
+    #[account(
+        mut,
+        constraint = referrer_info.key() != user_info.key() @ StakingError::InvalidReferrer,
+    )]
+    pub referrer_info: Option<Account<'info, UserInfo>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Stake tokens on behalf of another wallet
+#[derive(Accounts)]
+pub struct StakeFor<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    
+    /// CHECK: Only used to derive the beneficiary's user info PDA
+    pub beneficiary: UncheckedAccount<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Beneficiary's user info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), beneficiary.key().as_ref()],
+        bump,
+        constraint = user_info.owner == beneficiary.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// Payer's token account
+    #[account(
+        mut,
+        constraint = payer_token_account.owner == payer.key() @ StakingError::InvalidOwner,
+        constraint = payer_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+    )]
+    pub payer_token_account: Account<'info, TokenAccount>,
+    
+    /// Vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    /// Referrer's user info, credited with the referral on the beneficiary's first stake
+    #[account(
+        mut,
+        constraint = referrer_info.key() != user_info.key() @ StakingError::InvalidReferrer,
+    )]
+    pub referrer_info: Option<Account<'info, UserInfo>>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Unstake tokens
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// User token account
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
+        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    /// Vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Claim rewards
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// User token account
+    #[account(
+        mut,
+        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
+        constraint = user_token_account.mint == global_state.reward_mint() @ StakingError::InvalidMint,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    /// Reward vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.reward_vault() @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Compound rewards
+#[derive(Accounts)]
+pub struct CompoundRewards<'info> {
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Close an empty user account
+#[derive(Accounts)]
+pub struct CloseUser<'info> {
+    pub owner: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        mut,
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+        close = rent_receiver,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+    
+    /// CHECK: Only receives the rent of the closed user info; must be whoever paid it
+    #[account(
+        mut,
+        constraint = rent_receiver.key() == user_info.rent_receiver() @ StakingError::InvalidRentReceiver,
+    )]
+    pub rent_receiver: UncheckedAccount<'info>,
+    
+    /// CHECK: Referrer's user info PDA, verified in the handler; may already be closed
+    #[account(mut)]
+    pub referrer_info: Option<UncheckedAccount<'info>>,
+}
+
+/// Add tokens to reward pool
+#[derive(Accounts)]
+pub struct AddToRewardPool<'info> {
+    #[account(
+        mut,
+        constraint = global_state.has_role(Role::RewardFunder, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User token account
+    #[account(
+        mut,
+        constraint = user_token_account.owner == authority.key() @ StakingError::InvalidOwner,
+        constraint = user_token_account.mint == global_state.reward_mint() @ StakingError::InvalidMint,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+    
+    /// Reward vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.reward_vault() @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Donate tokens to the reward pool
+#[derive(Accounts)]
+pub struct DonateToRewardPool<'info> {
+    #[account(mut)]
+    pub donor: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Donor's cumulative donations to this pool
+    #[account(
+        init_if_needed,
+        payer = donor,
+        space = Donor::LEN,
+        seeds = [b"donor".as_ref(), global_state.key().as_ref(), donor.key().as_ref()],
+        bump,
+    )]
+    pub donor_info: Account<'info, Donor>,
+    
+    /// Donor's token account
+    #[account(
+        mut,
+        constraint = donor_token_account.owner == donor.key() @ StakingError::InvalidOwner,
+        constraint = donor_token_account.mint == global_state.reward_mint() @ StakingError::InvalidMint,
+    )]
+    pub donor_token_account: Account<'info, TokenAccount>,
+    
+    /// Reward vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.reward_vault() @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+/// Withdraw collected penalties
+#[derive(Accounts)]
+pub struct WithdrawPenalties<'info> {
+    #[account(
+        constraint = global_state.has_role(Role::FeeManager, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Vault token account
+    #[account(
+        mut,
+        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    
+    /// Token account receiving the penalties
+    #[account(
+        mut,
+        constraint = destination.mint == global_state.token_mint @ StakingError::InvalidMint,
+    )]
+    pub destination: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
+}
+
+/// View a user's position
+#[derive(Accounts)]
+pub struct GetUserInfo<'info> {
+    /// CHECK: Only used to derive the user info PDA
+    pub owner: UncheckedAccount<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// User info account
+    #[account(
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
+        bump,
+        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+    )]
+    pub user_info: Account<'info, UserInfo>,
 }
 
-impl GlobalState {
-    pub const LEN: usize = 8 + // discriminator
-        32 + // authority
-        32 + // token_mint
-        32 + // vault
-        8 + // reward_rate
-        8 + // unlock_duration
-        8 + // early_unstake_penalty
-        8 + // min_stake_amount
-        8 + // referral_reward_rate
-        8 + // total_staked
-        8 + // stakers_count
-        8 + // reward_pool
-        8 + // last_update_time
-        1; // bump
+/// View pool-wide figures
+#[derive(Accounts)]
+pub struct GetVaultInfo<'info> {
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+}
+
+/// Queue a staking parameter update
+#[derive(Accounts)]
+pub struct UpdateParameters<'info> {
+    #[account(
+        mut,
+        constraint = global_state.has_role(Role::ParameterAdmin, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
     
-    pub fn find_pda() -> (Pubkey, u8) {
-        Pubkey::find_program_address(
-            &[b"global_state".as_ref()],
-            &crate::ID,
-        )
-    }
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Pending parameter change account, only one change can be queued at a time
+    #[account(
+        init,
+        payer = authority,
+        space = PendingParameterChange::LEN,
+        seeds = [b"pending_parameters".as_ref(), global_state.key().as_ref()],
+        bump,
+    )]
+    pub pending_change: Account<'info, PendingParameterChange>,
+    
+    pub system_program: Program<'info, System>,
 }
 
-/// Initialize the staking vault and global state
+/// Apply a queued parameter change
 #[derive(Accounts)]
-pub struct Initialize<'info> {
+pub struct ApplyPendingParameters<'info> {
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Pending parameter change account
+    #[account(
+        mut,
+        seeds = [b"pending_parameters".as_ref(), global_state.key().as_ref()],
+        bump = pending_change.bump,
+        close = rent_receiver,
+    )]
+    pub pending_change: Account<'info, PendingParameterChange>,
+    
+    /// CHECK: Only receives the rent of the closed pending change account
+    #[account(
+        mut,
+        constraint = rent_receiver.key() == pending_change.queued_by @ StakingError::InvalidOwner,
+    )]
+    pub rent_receiver: UncheckedAccount<'info>,
+}
+
+/// Cancel a queued parameter change
+#[derive(Accounts)]
+pub struct CancelPendingParameters<'info> {
+    #[account(
+        constraint = global_state.has_role(Role::ParameterAdmin, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Pending parameter change account
+    #[account(
+        mut,
+        seeds = [b"pending_parameters".as_ref(), global_state.key().as_ref()],
+        bump = pending_change.bump,
+        close = rent_receiver,
+    )]
+    pub pending_change: Account<'info, PendingParameterChange>,
+    
+    /// CHECK: Only receives the rent of the closed pending change account
+    #[account(
+        mut,
+        constraint = rent_receiver.key() == pending_change.queued_by @ StakingError::InvalidOwner,
+    )]
+    pub rent_receiver: UncheckedAccount<'info>,
+}
+
+/// Set up the admin council
+#[derive(Accounts)]
+pub struct InitializeCouncil<'info> {
+    #[account(
+        mut,
+        constraint = authority.key() == global_state.authority @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account, kept when the council disables itself
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = AdminCouncil::LEN,
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Create a council proposal
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
+    #[account(
+        mut,
+        constraint = council.is_member(&proposer.key()) @ StakingError::NotCouncilMember,
+    )]
+    pub proposer: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+        constraint = global_state.council_enabled @ StakingError::CouncilNotEnabled,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account
+    #[account(
+        mut,
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump = council.bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    /// Proposal account
+    #[account(
+        init,
+        payer = proposer,
+        space = Proposal::LEN,
+        seeds = [b"proposal".as_ref(), council.key().as_ref(), &council.proposal_count.to_le_bytes()],
+        bump,
+    )]
+    pub proposal: Account<'info, Proposal>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Approve a council proposal
+#[derive(Accounts)]
+pub struct ApproveProposal<'info> {
+    #[account(
+        constraint = council.is_member(&member.key()) @ StakingError::NotCouncilMember,
+    )]
+    pub member: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+        constraint = global_state.council_enabled @ StakingError::CouncilNotEnabled,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account
+    #[account(
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump = council.bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    /// Proposal account
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), council.key().as_ref(), &proposal.id.to_le_bytes()],
+        bump = proposal.bump,
+    )]
+    pub proposal: Account<'info, Proposal>,
+}
+
+/// Execute an approved council proposal
+#[derive(Accounts)]
+pub struct ExecuteProposal<'info> {
+    /// Anyone can execute once the threshold is reached, pays for the pending change account
     #[account(mut)]
+    pub executor: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+        constraint = global_state.council_enabled @ StakingError::CouncilNotEnabled,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account
+    #[account(
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump = council.bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    /// Proposal account
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), council.key().as_ref(), &proposal.id.to_le_bytes()],
+        bump = proposal.bump,
+    )]
+    pub proposal: Account<'info, Proposal>,
+    
+    /// Pending parameter change account, only one change can be queued at a time
+    #[account(
+        init,
+        payer = executor,
+        space = PendingParameterChange::LEN,
+        seeds = [b"pending_parameters".as_ref(), global_state.key().as_ref()],
+        bump,
+    )]
+    pub pending_change: Account<'info, PendingParameterChange>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+/// Execute an approved proposal cancelling the queued parameter change
+#[derive(Accounts)]
+pub struct ExecuteCancelProposal<'info> {
+    /// Anyone can execute once the threshold is reached
+    pub executor: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+        constraint = global_state.council_enabled @ StakingError::CouncilNotEnabled,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account
+    #[account(
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump = council.bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    /// Proposal account
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), council.key().as_ref(), &proposal.id.to_le_bytes()],
+        bump = proposal.bump,
+    )]
+    pub proposal: Account<'info, Proposal>,
+    
+    /// Pending parameter change account
+    #[account(
+        mut,
+        seeds = [b"pending_parameters".as_ref(), global_state.key().as_ref()],
+        bump = pending_change.bump,
+        close = rent_receiver,
+    )]
+    pub pending_change: Account<'info, PendingParameterChange>,
+    
+    /// CHECK: Only receives the rent of the closed pending change account
+    #[account(
+        mut,
+        constraint = rent_receiver.key() == pending_change.queued_by @ StakingError::InvalidOwner,
+    )]
+    pub rent_receiver: UncheckedAccount<'info>,
+}
+
+/// Execute an approved council membership proposal
+#[derive(Accounts)]
+pub struct ExecuteCouncilProposal<'info> {
+    /// Anyone can execute once the threshold is reached
+    pub executor: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+        constraint = global_state.council_enabled @ StakingError::CouncilNotEnabled,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Admin council account
+    #[account(
+        mut,
+        seeds = [b"admin_council".as_ref(), global_state.key().as_ref()],
+        bump = council.bump,
+    )]
+    pub council: Account<'info, AdminCouncil>,
+    
+    /// Proposal account
+    #[account(
+        mut,
+        seeds = [b"proposal".as_ref(), council.key().as_ref(), &proposal.id.to_le_bytes()],
+        bump = proposal.bump,
+    )]
+    pub proposal: Account<'info, Proposal>,
+}
+
+/// Create a reward stream
+#[derive(Accounts)]
+#[instruction(slot: u8)]
+pub struct CreateRewardStream<'info> {
+    #[account(
+        mut,
+        constraint = global_state.has_role(Role::RewardFunder, &authority.key()) @ StakingError::Unauthorized,
+    )]
     pub authority: Signer<'info>,
     
-    /// Check if the token mint is valid
+    /// Global state account
     #[account(
-        constraint = token_mint.mint_authority == COption::Some(authority.key()) @ StakingError::InvalidMintAuthority
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
     )]
-    pub token_mint: Account<'info, anchor_spl::token::Mint>,
+    pub global_state: Account<'info, GlobalState>,
     
-    /// Token account that will act as the vault
+    /// Reward stream account
     #[account(
         init,
         payer = authority,
-        token::mint = token_mint,
+        space = RewardStream::LEN,
+        seeds = [b"reward_stream".as_ref(), global_state.key().as_ref(), &[slot]],
+        bump,
+    )]
+    pub stream: Account<'info, RewardStream>,
+    
+    /// Token the stream pays out
+    pub reward_mint: Account<'info, anchor_spl::token::Mint>,
+    
+    /// Vault holding the stream's rewards
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_mint,
         token::authority = global_state,
+        seeds = [b"stream_vault".as_ref(), stream.key().as_ref()],
+        bump,
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub stream_vault: Account<'info, TokenAccount>,
+    
+    pub system_program: Program<'info, System>,
+    pub token_program: Program<'info, Token>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+/// Start a budgeted campaign
+#[derive(Accounts)]
+#[instruction(slot: u8)]
+pub struct StartCampaign<'info> {
+    #[account(
+        mut,
+        constraint = global_state.has_role(Role::RewardFunder, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    
+    /// Reward stream account running the campaign
+    #[account(
+        init,
+        payer = authority,
+        space = RewardStream::LEN,
+        seeds = [b"reward_stream".as_ref(), global_state.key().as_ref(), &[slot]],
+        bump,
+    )]
+    pub stream: Account<'info, RewardStream>,
     
-    /// Global state account
+    /// Token the campaign pays out
+    pub reward_mint: Account<'info, anchor_spl::token::Mint>,
+    
+    /// Vault holding the campaign budget
     #[account(
         init,
         payer = authority,
-        space = GlobalState::LEN,
-        seeds = [b"global_state".as_ref()],
+        token::mint = reward_mint,
+        token::authority = global_state,
+        seeds = [b"stream_vault".as_ref(), stream.key().as_ref()],
         bump,
     )]
-    pub global_state: Account<'info, GlobalState>,
+    pub stream_vault: Account<'info, TokenAccount>,
+    
+    /// Funder's token account
+    #[account(
+        mut,
+        constraint = funder_token_account.owner == authority.key() @ StakingError::InvalidOwner,
+        constraint = funder_token_account.mint == reward_mint.key() @ StakingError::InvalidMint,
+    )]
+    pub funder_token_account: Account<'info, TokenAccount>,
     
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, Token>,
     pub rent: Sysvar<'info, Rent>,
 }
 
-/// Register a new user
+/// Withdraw a campaign's undistributed budget
 #[derive(Accounts)]
-pub struct RegisterUser<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+pub struct WithdrawCampaignLeftover<'info> {
+    #[account(
+        constraint = funder.key() == stream.funder @ StakingError::Unauthorized,
+    )]
+    pub funder: Signer<'info>,
     
-    /// User info account
+    /// Global state account
     #[account(
-        init,
-        payer = owner,
-        space = UserInfo::LEN,
-        seeds = [b"user_info".as_ref(), owner.key().as_ref()],
-        bump,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
     )]
-    pub user_info: Account<'info, UserInfo>,
+    pub global_state: Account<'info, GlobalState>,
     
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+    /// Reward stream account running the campaign
+    #[account(
+        mut,
+        seeds = [b"reward_stream".as_ref(), global_state.key().as_ref(), &[stream.slot]],
+        bump = stream.bump,
+    )]
+    pub stream: Account<'info, RewardStream>,
+    
+    /// Stream vault token account
+    #[account(
+        mut,
+        constraint = stream_vault.key() == stream.vault @ StakingError::InvalidVault,
+    )]
+    pub stream_vault: Account<'info, TokenAccount>,
+    
+    /// Funder's token account
+    #[account(
+        mut,
+        constraint = funder_token_account.owner == funder.key() @ StakingError::InvalidOwner,
+        constraint = funder_token_account.mint == stream.mint @ StakingError::InvalidMint,
+    )]
+    pub funder_token_account: Account<'info, TokenAccount>,
+    
+    pub token_program: Program<'info, Token>,
 }
 
-/// Stake tokens
+/// Fund a reward stream
 #[derive(Accounts)]
-pub struct Stake<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+pub struct FundRewardStream<'info> {
+    #[account(
+        constraint = global_state.has_role(Role::RewardFunder, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
     
     /// Global state account
     #[account(
-        mut,
-        seeds = [b"global_state".as_ref()],
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
         bump = global_state.bump,
     )]
     pub global_state: Account<'info, GlobalState>,
     
-    /// User info account
+    /// Reward stream account
     #[account(
-        mut,
-        seeds = [b"user_info".as_ref(), owner.key().as_ref()],
-        bump,
-        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+        seeds = [b"reward_stream".as_ref(), global_state.key().as_ref(), &[stream.slot]],
+        bump = stream.bump,
     )]
-    pub user_info: Account<'info, UserInfo>,
+    pub stream: Account<'info, RewardStream>,
     
-    /// User token account
+    /// Funder's token account
     #[account(
         mut,
-        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
-        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+        constraint = funder_token_account.owner == authority.key() @ StakingError::InvalidOwner,
+        constraint = funder_token_account.mint == stream.mint @ StakingError::InvalidMint,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub funder_token_account: Account<'info, TokenAccount>,
     
-    /// Vault token account
+    /// Stream vault token account
     #[account(
         mut,
-        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+        constraint = stream_vault.key() == stream.vault @ StakingError::InvalidVault,
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub stream_vault: Account<'info, TokenAccount>,
     
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
 }
 
-/// Unstake tokens
+/// Close a finished reward stream
 #[derive(Accounts)]
-pub struct Unstake<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+pub struct CloseRewardStream<'info> {
+    #[account(
+        mut,
+        constraint = global_state.has_role(Role::RewardFunder, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
     
     /// Global state account
     #[account(
         mut,
-        seeds = [b"global_state".as_ref()],
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
         bump = global_state.bump,
     )]
     pub global_state: Account<'info, GlobalState>,
     
-    /// User info account
+    /// Reward stream account
     #[account(
         mut,
-        seeds = [b"user_info".as_ref(), owner.key().as_ref()],
-        bump,
-        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+        seeds = [b"reward_stream".as_ref(), global_state.key().as_ref(), &[stream.slot]],
+        bump = stream.bump,
+        close = authority,
     )]
-    pub user_info: Account<'info, UserInfo>,
+    pub stream: Account<'info, RewardStream>,
     
-    /// User token account
+    /// Stream vault token account
     #[account(
         mut,
-        constraint = user_token_account.owner == owner.key() @ StakingError::InvalidOwner,
-        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+        constraint = stream_vault.key() == stream.vault @ StakingError::InvalidVault,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub stream_vault: Account<'info, TokenAccount>,
     
-    /// Vault token account
+    /// Token account receiving what is left in the vault
     #[account(
         mut,
-        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+        constraint = destination.mint == stream.mint @ StakingError::InvalidMint,
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub destination: Account<'info, TokenAccount>,
     
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
 }
 
-/// Claim rewards
+/// Set the pause bitmask
 #[derive(Accounts)]
-pub struct ClaimRewards<'info> {
+pub struct SetPause<'info> {
+    #[account(
+        constraint = global_state.has_role(Role::Pauser, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+}
+
+/// Toggle emergency mode
+#[derive(Accounts)]
+pub struct SetEmergency<'info> {
+    #[account(
+        constraint = global_state.has_role(Role::Pauser, &authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
+    
+    /// Global state account
+    #[account(
+        mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+}
+
+/// Withdraw principal during an emergency
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
     #[account(mut)]
     pub owner: Signer<'info>,
     
     /// Global state account
     #[account(
         mut,
-        seeds = [b"global_state".as_ref()],
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
         bump = global_state.bump,
     )]
     pub global_state: Account<'info, GlobalState>,
@@ -616,7 +3974,7 @@ pub struct ClaimRewards<'info> {
     /// User info account
     #[account(
         mut,
-        seeds = [b"user_info".as_ref(), owner.key().as_ref()],
+        seeds = [b"user_info".as_ref(), global_state.pool_seed(&global_state.key()), owner.key().as_ref()],
         bump,
         constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
     )]
@@ -638,40 +3996,63 @@ pub struct ClaimRewards<'info> {
     pub vault: Account<'info, TokenAccount>,
     
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
 }
 
-/// Compound rewards
+/// Assign or revoke a role
 #[derive(Accounts)]
-pub struct CompoundRewards<'info> {
-    #[account(mut)]
-    pub owner: Signer<'info>,
+pub struct SetRole<'info> {
+    #[account(
+        constraint = authority.key() == global_state.authority @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
     
     /// Global state account
     #[account(
         mut,
-        seeds = [b"global_state".as_ref()],
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
         bump = global_state.bump,
     )]
     pub global_state: Account<'info, GlobalState>,
+}
+
+/// Propose a new authority
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    #[account(
+        constraint = authority.key() == global_state.authority @ StakingError::Unauthorized,
+    )]
+    pub authority: Signer<'info>,
     
-    /// User info account
+    /// Global state account
     #[account(
         mut,
-        seeds = [b"user_info".as_ref(), owner.key().as_ref()],
-        bump,
-        constraint = user_info.owner == owner.key() @ StakingError::InvalidOwner,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
     )]
-    pub user_info: Account<'info, UserInfo>,
-    
-    pub system_program: Program<'info, System>,
+    pub global_state: Account<'info, GlobalState>,
 }
 
-/// Add tokens to reward pool
+/// Accept a pending authority transfer
 #[derive(Accounts)]
-pub struct AddToRewardPool<'info> {
+pub struct AcceptAuthority<'info> {
+    #[account(
+        constraint = global_state.pending_authority == Some(new_authority.key()) @ StakingError::Unauthorized,
+    )]
+    pub new_authority: Signer<'info>,
+    
+    /// Global state account
     #[account(
         mut,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
+    )]
+    pub global_state: Account<'info, GlobalState>,
+}
+
+/// Cancel a pending authority transfer
+#[derive(Accounts)]
+pub struct CancelAuthorityTransfer<'info> {
+    #[account(
         constraint = authority.key() == global_state.authority @ StakingError::Unauthorized,
     )]
     pub authority: Signer<'info>,
@@ -679,49 +4060,408 @@ pub struct AddToRewardPool<'info> {
     /// Global state account
     #[account(
         mut,
-        seeds = [b"global_state".as_ref()],
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
         bump = global_state.bump,
     )]
     pub global_state: Account<'info, GlobalState>,
+}
+
+/// Migrate a legacy user info account
+#[derive(Accounts)]
+pub struct MigrateUserInfo<'info> {
+    /// Owner or crank paying for the additional rent
+    #[account(mut)]
+    pub payer: Signer<'info>,
     
-    /// User token account
+    /// CHECK: Legacy layout can't be deserialized as `UserInfo`; owner, discriminator
+    /// and PDA derivation are verified in the handler
     #[account(
         mut,
-        constraint = user_token_account.owner == authority.key() @ StakingError::InvalidOwner,
-        constraint = user_token_account.mint == global_state.token_mint @ StakingError::InvalidMint,
+        owner = crate::ID,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub user_info: UncheckedAccount<'info>,
     
-    /// Vault token account
+    /// Global state account, source of defaults for new fields
     #[account(
         mut,
-        constraint = vault.key() == global_state.vault @ StakingError::InvalidVault,
+        seeds = [b"global_state".as_ref(), global_state.seed_mint(), global_state.seed_pool_id().as_slice()],
+        bump = global_state.bump,
     )]
-    pub vault: Account<'info, TokenAccount>,
+    pub global_state: Account<'info, GlobalState>,
     
-    pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
 }
 
-/// Update staking parameters
+/// Migrate the legacy global state account
 #[derive(Accounts)]
-pub struct UpdateParameters<'info> {
-    #[account(
-        constraint = authority.key() == global_state.authority @ StakingError::Unauthorized,
-    )]
+pub struct MigrateGlobalState<'info> {
+    /// Authority recorded in the legacy global state, pays the additional rent
+    #[account(mut)]
     pub authority: Signer<'info>,
     
-    /// Global state account
+    /// CHECK: Legacy layout can't be deserialized as `GlobalState`; owner, discriminator
+    /// and PDA derivation are verified in the handler, authority is checked against its data
     #[account(
         mut,
-        seeds = [b"global_state".as_ref()],
-        bump = global_state.bump,
+        owner = crate::ID,
     )]
-    pub global_state: Account<'info, GlobalState>,
+    pub global_state: UncheckedAccount<'info>,
     
     pub system_program: Program<'info, System>,
 }
 
+/// Emitted when the program is initialized
+#[event]
+pub struct Initialized {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub pool_id: u64,
+    pub vault: Pubkey,
+    pub reward_mint: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when a user registers
+#[event]
+pub struct Registered {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub referrer: Option<Pubkey>,
+    pub timestamp: i64,
+}
+
+/// Emitted when tokens are staked
+#[event]
+pub struct Staked {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64,  // User's stake after this deposit
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted alongside `Staked` when the stake was funded by another wallet
+#[event]
+pub struct StakedFor {
+    pub pool: Pubkey,
+    pub payer: Pubkey,
+    pub beneficiary: Pubkey,
+    pub amount: u64,
+    pub locked_amount: u64,  // Beneficiary's gift-locked stake after this deposit
+    pub gift_unlock_time: i64,  // When the gift lock ends; the beneficiary's own lock is unchanged
+    pub timestamp: i64,
+}
+
+/// Emitted when tokens are unstaked; `amount` = `withdrawn` + `penalty`
+#[event]
+pub struct Unstaked {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub penalty: u64,  // Early unstake penalty moved to the reward pool
+    pub withdrawn: u64,  // Tokens sent to the user
+    pub staked_amount: u64,  // User's stake after this withdrawal
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when rewards are claimed
+#[event]
+pub struct RewardsClaimed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub reward_pool: u64,  // Reward pool remaining after the claim
+    pub timestamp: i64,
+}
+
+/// Emitted when rewards are compounded into the stake
+#[event]
+pub struct Compounded {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub staked_amount: u64,
+    pub total_staked: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a referrer is credited with a staking referral
+#[event]
+pub struct ReferralCredited {
+    pub pool: Pubkey,
+    pub referrer: Pubkey,
+    pub user: Pubkey,
+    pub referral_count: u64,  // Referrer's count after this referral
+    pub timestamp: i64,
+}
+
+/// Emitted when a user account is closed
+#[event]
+pub struct UserClosed {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when tokens are added to the reward pool
+#[event]
+pub struct RewardPoolFunded {
+    pub pool: Pubkey,
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub reward_pool: u64,
+    pub undistributed_rewards: u64,  // Still dripping into the reward pool
+    pub drip_end_time: i64,
+    pub timestamp: i64,
+}
+
+/// Emitted when stream rewards are claimed
+#[event]
+pub struct StreamRewardsClaimed {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a reward stream is created
+#[event]
+pub struct RewardStreamCreated {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub id: u64,
+    pub slot: u8,
+    pub mint: Pubkey,
+    pub reward_rate: u64,
+    pub start_time: i64,
+    pub end_time: i64,
+}
+
+/// Emitted when tokens are added to a reward stream
+#[event]
+pub struct RewardStreamFunded {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a budgeted campaign starts
+#[event]
+pub struct CampaignStarted {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub funder: Pubkey,
+    pub budget: u64,
+    pub start_time: i64,
+    pub end_time: i64,
+}
+
+/// Emitted when a campaign's undistributed budget is returned to its funder
+#[event]
+pub struct CampaignLeftoverWithdrawn {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub funder: Pubkey,
+    pub distributed: u64,
+    pub leftover: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a reward stream is closed
+#[event]
+pub struct RewardStreamClosed {
+    pub pool: Pubkey,
+    pub stream: Pubkey,
+    pub remaining: u64,  // Tokens swept out of the vault
+    pub timestamp: i64,
+}
+
+/// Emitted when collected penalties are withdrawn
+#[event]
+pub struct PenaltiesWithdrawn {
+    pub pool: Pubkey,
+    pub withdrawn_by: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when anyone donates to the reward pool
+#[event]
+pub struct RewardPoolDonated {
+    pub pool: Pubkey,
+    pub donor: Pubkey,
+    pub amount: u64,
+    pub total_donated: u64,  // Donor's cumulative donations to this pool
+    pub reward_pool: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when principal is withdrawn in emergency mode
+#[event]
+pub struct EmergencyWithdrawn {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub forfeited_rewards: u64,
+    pub timestamp: i64,
+}
+
+/// Emitted when the pause bitmask changes
+#[event]
+pub struct PauseUpdated {
+    pub pool: Pubkey,
+    pub paused: u8,
+    pub updated_by: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when emergency mode is toggled
+#[event]
+pub struct EmergencyModeUpdated {
+    pub pool: Pubkey,
+    pub enabled: bool,
+    pub updated_by: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when a role is assigned or revoked
+#[event]
+pub struct RoleUpdated {
+    pub pool: Pubkey,
+    pub role: Role,
+    pub holder: Option<Pubkey>,
+    pub timestamp: i64,
+}
+
+/// Emitted when a new authority is proposed
+#[event]
+pub struct AuthorityTransferProposed {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub pending_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when the pending authority accepts
+#[event]
+pub struct AuthorityTransferred {
+    pub pool: Pubkey,
+    pub previous_authority: Pubkey,
+    pub authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when a pending authority transfer is cancelled
+#[event]
+pub struct AuthorityTransferCancelled {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub cancelled_authority: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when the admin council is set up
+#[event]
+pub struct CouncilInitialized {
+    pub pool: Pubkey,
+    pub members: Vec<Pubkey>,
+    pub threshold: u8,
+    pub timestamp: i64,
+}
+
+/// Emitted when the council's members or threshold change, or it is disabled
+#[event]
+pub struct CouncilUpdated {
+    pub pool: Pubkey,
+    pub members: Vec<Pubkey>,
+    pub threshold: u8,
+    pub enabled: bool,
+    pub timestamp: i64,
+}
+
+/// Emitted when a council proposal is created
+#[event]
+pub struct ProposalCreated {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub proposer: Pubkey,
+    pub action: ProposalAction,
+    pub timestamp: i64,
+}
+
+/// Emitted when a council member approves a proposal
+#[event]
+pub struct ProposalApproved {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub member: Pubkey,
+    pub approvals: u8,
+    pub timestamp: i64,
+}
+
+/// Emitted when an approved proposal is executed
+#[event]
+pub struct ProposalExecuted {
+    pub pool: Pubkey,
+    pub id: u64,
+    pub executor: Pubkey,
+    pub timestamp: i64,
+}
+
+/// Emitted when a user info account is migrated
+#[event]
+pub struct UserInfoMigrated {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    pub version: u8,
+    pub timestamp: i64,
+}
+
+/// Emitted when the global state account is migrated
+#[event]
+pub struct GlobalStateMigrated {
+    pub pool: Pubkey,
+    pub version: u8,
+    pub timestamp: i64,
+}
+
+/// Emitted when a parameter change is queued behind the timelock
+#[event]
+pub struct ParameterChangeQueued {
+    pub pool: Pubkey,
+    pub change: ParameterChange,
+    pub queued_by: Pubkey,
+    pub effective_time: i64,
+}
+
+/// Emitted when a queued parameter change takes effect, with the resulting parameters
+#[event]
+pub struct ParametersUpdated {
+    pub pool: Pubkey,
+    pub reward_rate: u64,
+    pub unlock_duration: i64,
+    pub early_unstake_penalty: u64,
+    pub min_stake_amount: u64,
+    pub referral_reward_rate: u64,
+    pub parameter_change_delay: i64,
+    pub timestamp: i64,
+}
+
+/// Emitted when a queued parameter change is cancelled
+#[event]
+pub struct ParameterChangeCancelled {
+    pub pool: Pubkey,
+    pub change: ParameterChange,
+    pub cancelled_at: i64,
+}
+
 #[error_code]
 pub enum StakingError {
     #[msg("Unauthorized operation")]
@@ -756,4 +4496,1361 @@ pub enum StakingError {
     
     #[msg("Referral reward rate too high (max 20%)")]
     ReferralRateTooHigh,
-}
\ No newline at end of file
+    
+    #[msg("Account already uses the current layout")]
+    AlreadyMigrated,
+    
+    #[msg("No pending authority transfer")]
+    NoPendingAuthority,
+    
+    #[msg("Parameter changes require an approved council proposal")]
+    CouncilApprovalRequired,
+    
+    #[msg("Invalid council members or threshold")]
+    InvalidCouncil,
+    
+    #[msg("Signer is not a council member")]
+    NotCouncilMember,
+    
+    #[msg("Proposal already approved by this member")]
+    AlreadyApproved,
+    
+    #[msg("Proposal already executed")]
+    ProposalAlreadyExecuted,
+    
+    #[msg("Proposal has not reached the approval threshold")]
+    InsufficientApprovals,
+    
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    
+    #[msg("Invalid parameter value")]
+    InvalidParameter,
+    
+    #[msg("Parameter change timelock has not elapsed")]
+    TimelockNotElapsed,
+    
+    #[msg("Operation is paused")]
+    Paused,
+    
+    #[msg("Emergency mode is not enabled")]
+    NotInEmergency,
+    
+    #[msg("Invalid referrer")]
+    InvalidReferrer,
+    
+    #[msg("Account still holds staked tokens or rewards")]
+    AccountNotEmpty,
+    
+    #[msg("Rewards are paid in a different token than the one staked")]
+    RewardMintMismatch,
+    
+    #[msg("No penalties have been collected")]
+    NoPenaltiesCollected,
+    
+    #[msg("Invalid reward stream")]
+    InvalidRewardStream,
+    
+    #[msg("Every live reward stream must be passed in remaining accounts")]
+    MissingRewardStream,
+    
+    #[msg("Reward stream is still running or within its claim window")]
+    RewardStreamActive,
+    
+    #[msg("Gifted stake is locked until its gift lock ends")]
+    StakeLocked,
+    
+    #[msg("Rent must be returned to the account that paid it")]
+    InvalidRentReceiver,
+    
+    #[msg("Proposal action can't be executed by this instruction")]
+    InvalidProposalAction,
+    
+    #[msg("Admin council is not enabled")]
+    CouncilNotEnabled,
+    
+    #[msg("Proposal was created before the council was last enabled")]
+    StaleProposal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn zeroed<T: AccountDeserialize>(len: usize) -> T {
+        T::try_deserialize_unchecked(&mut &vec![0u8; len][..]).unwrap()
+    }
+    
+    // In-memory runtime for driving handlers through the program entrypoint. Accounts are
+    // laid out as the loader serializes them, so reallocs and closes behave as on chain, and
+    // state is only written back when the instruction succeeds. System and token program
+    // CPIs are emulated for the instructions the handlers use.
+    
+    use anchor_lang::solana_program::entrypoint::{
+        deserialize, ProgramResult, MAX_PERMITTED_DATA_INCREASE, NON_DUP_MARKER,
+    };
+    use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+    use anchor_lang::solana_program::program_error::ProgramError;
+    use anchor_lang::solana_program::program_option::COption;
+    use anchor_lang::solana_program::program_pack::Pack;
+    use anchor_lang::solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use anchor_lang::solana_program::program_utils::limited_deserialize;
+    use anchor_lang::solana_program::system_instruction::SystemInstruction;
+    use anchor_lang::solana_program::{bpf_loader, system_program, sysvar};
+    use anchor_lang::{InstructionData, ToAccountMetas};
+    use anchor_spl::token::spl_token;
+    use anchor_spl::token::spl_token::instruction::TokenInstruction;
+    use anchor_spl::token::spl_token::state::{Account as SplAccount, AccountState};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    
+    const SOL: u64 = 1_000_000_000;
+    
+    thread_local! {
+        static NOW: Cell<i64> = const { Cell::new(0) };
+    }
+    
+    static INSTALL_STUBS: std::sync::Once = std::sync::Once::new();
+    
+    struct TestStubs;
+    
+    impl SyscallStubs for TestStubs {
+        fn sol_log(&self, _message: &str) {}
+        
+        fn sol_log_data(&self, _fields: &[&[u8]]) {}
+        
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock {
+                unix_timestamp: NOW.with(Cell::get),
+                ..Clock::default()
+            };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            0
+        }
+        
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            0
+        }
+        
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            let find = |key: &Pubkey| {
+                account_infos
+                    .iter()
+                    .find(|info| info.key == key)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)
+            };
+            for meta in instruction.accounts.iter().filter(|meta| meta.is_signer) {
+                let signed = find(&meta.pubkey)?.is_signer
+                    || signers_seeds.iter().any(|seeds| {
+                        Pubkey::create_program_address(seeds, &crate::ID) == Ok(meta.pubkey)
+                    });
+                if !signed {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+            }
+            let accounts = instruction.accounts
+                .iter()
+                .map(|meta| find(&meta.pubkey))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            
+            if instruction.program_id == system_program::ID {
+                invoke_system(&accounts, &instruction.data)
+            } else if instruction.program_id == spl_token::ID {
+                invoke_token(&accounts, &instruction.data)
+            } else {
+                Err(ProgramError::IncorrectProgramId)
+            }
+        }
+    }
+    
+    fn move_lamports(from: &AccountInfo, to: &AccountInfo, lamports: u64) -> ProgramResult {
+        let remaining = from.lamports()
+            .checked_sub(lamports)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        **from.try_borrow_mut_lamports()? = remaining;
+        **to.try_borrow_mut_lamports()? += lamports;
+        Ok(())
+    }
+    
+    fn invoke_system(accounts: &[&AccountInfo], data: &[u8]) -> ProgramResult {
+        let instruction: SystemInstruction = limited_deserialize(data, 1024)
+            .map_err(|_| ProgramError::InvalidInstructionData)?;
+        match instruction {
+            SystemInstruction::CreateAccount { lamports, space, owner } => {
+                if accounts[1].lamports() > 0 {
+                    return Err(ProgramError::AccountAlreadyInitialized);
+                }
+                move_lamports(accounts[0], accounts[1], lamports)?;
+                accounts[1].realloc(space as usize, true)?;
+                accounts[1].assign(&owner);
+            }
+            SystemInstruction::Transfer { lamports } => {
+                move_lamports(accounts[0], accounts[1], lamports)?;
+            }
+            SystemInstruction::Allocate { space } => accounts[0].realloc(space as usize, true)?,
+            SystemInstruction::Assign { owner } => accounts[0].assign(&owner),
+            _ => return Err(ProgramError::InvalidInstructionData),
+        }
+        Ok(())
+    }
+    
+    fn invoke_token(accounts: &[&AccountInfo], data: &[u8]) -> ProgramResult {
+        match TokenInstruction::unpack(data)? {
+            TokenInstruction::Transfer { amount } => {
+                let mut source = SplAccount::unpack(&accounts[0].try_borrow_data()?)?;
+                let mut destination = SplAccount::unpack(&accounts[1].try_borrow_data()?)?;
+                if source.owner != *accounts[2].key || source.mint != destination.mint {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                source.amount = source.amount
+                    .checked_sub(amount)
+                    .ok_or(ProgramError::InsufficientFunds)?;
+                destination.amount += amount;
+                SplAccount::pack(source, &mut accounts[0].try_borrow_mut_data()?)?;
+                SplAccount::pack(destination, &mut accounts[1].try_borrow_mut_data()?)?;
+            }
+            TokenInstruction::CloseAccount => {
+                let account = SplAccount::unpack(&accounts[0].try_borrow_data()?)?;
+                if account.owner != *accounts[2].key || account.amount != 0 {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+                move_lamports(accounts[0], accounts[1], accounts[0].lamports())?;
+                accounts[0].realloc(0, false)?;
+                accounts[0].assign(&system_program::ID);
+            }
+            _ => return Err(ProgramError::InvalidInstructionData),
+        }
+        Ok(())
+    }
+    
+    #[derive(Clone, Default)]
+    struct TestAccount {
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+        executable: bool,
+    }
+    
+    struct TestRuntime {
+        accounts: HashMap<Pubkey, TestAccount>,
+    }
+    
+    impl TestRuntime {
+        fn new() -> Self {
+            INSTALL_STUBS.call_once(|| {
+                set_syscall_stubs(Box::new(TestStubs));
+            });
+            NOW.with(|now| now.set(1_000_000));
+            
+            let mut runtime = Self { accounts: HashMap::new() };
+            for program in [crate::ID, system_program::ID, spl_token::ID] {
+                runtime.accounts.insert(program, TestAccount {
+                    lamports: 1,
+                    owner: bpf_loader::ID,
+                    executable: true,
+                    ..TestAccount::default()
+                });
+            }
+            
+            // Rent sysvar in its bincode layout
+            let rent = Rent::default();
+            let mut data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+            data.extend(rent.exemption_threshold.to_le_bytes());
+            data.push(rent.burn_percent);
+            runtime.accounts.insert(sysvar::rent::ID, TestAccount {
+                lamports: 1,
+                data,
+                owner: sysvar::ID,
+                executable: false,
+            });
+            
+            runtime
+        }
+        
+        fn now(&self) -> i64 {
+            NOW.with(Cell::get)
+        }
+        
+        fn warp(&self, seconds: i64) {
+            NOW.with(|now| now.set(now.get() + seconds));
+        }
+        
+        /// New system account holding some SOL
+        fn wallet(&mut self) -> Pubkey {
+            let key = Pubkey::new_unique();
+            self.accounts.insert(key, TestAccount {
+                lamports: 10 * SOL,
+                ..TestAccount::default()
+            });
+            key
+        }
+        
+        fn lamports(&self, key: &Pubkey) -> u64 {
+            self.accounts.get(key).map_or(0, |account| account.lamports)
+        }
+        
+        fn exists(&self, key: &Pubkey) -> bool {
+            self.lamports(key) > 0
+        }
+        
+        fn set_account<T: AccountSerialize>(&mut self, key: Pubkey, account: &T, len: usize) {
+            let mut data = Vec::new();
+            account.try_serialize(&mut data).unwrap();
+            data.resize(data.len().max(len), 0);
+            self.set_raw_account(key, data);
+        }
+        
+        fn set_raw_account(&mut self, key: Pubkey, data: Vec<u8>) {
+            self.accounts.insert(key, TestAccount {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: crate::ID,
+                executable: false,
+            });
+        }
+        
+        fn account<T: AccountDeserialize>(&self, key: &Pubkey) -> T {
+            T::try_deserialize(&mut &self.accounts[key].data[..]).unwrap()
+        }
+        
+        fn set_token_account(&mut self, key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) {
+            let mut data = vec![0; SplAccount::LEN];
+            let account = SplAccount {
+                mint,
+                owner,
+                amount,
+                delegate: COption::None,
+                state: AccountState::Initialized,
+                is_native: COption::None,
+                delegated_amount: 0,
+                close_authority: COption::None,
+            };
+            SplAccount::pack(account, &mut data).unwrap();
+            self.accounts.insert(key, TestAccount {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: spl_token::ID,
+                executable: false,
+            });
+        }
+        
+        fn token_amount(&self, key: &Pubkey) -> u64 {
+            SplAccount::unpack(&self.accounts[key].data).unwrap().amount
+        }
+        
+        fn process(&mut self, accounts: impl ToAccountMetas, args: impl InstructionData) -> ProgramResult {
+            self.process_with(accounts, args, Vec::new())
+        }
+        
+        /// Run one instruction, passing `remaining` after the declared accounts
+        fn process_with(
+            &mut self,
+            accounts: impl ToAccountMetas,
+            args: impl InstructionData,
+            remaining: Vec<AccountMeta>,
+        ) -> ProgramResult {
+            let mut metas = accounts.to_account_metas(None);
+            metas.extend(remaining);
+            let mut input = self.serialize(&metas, &args.data());
+            
+            let (program_id, infos, data) = unsafe { deserialize(input.as_mut_ptr() as *mut u8) };
+            let result = crate::entry(program_id, &infos, data);
+            if result.is_ok() {
+                for info in infos.iter() {
+                    if info.lamports() == 0 {
+                        self.accounts.remove(info.key);
+                    } else {
+                        self.accounts.insert(*info.key, TestAccount {
+                            lamports: info.lamports(),
+                            data: info.try_borrow_data().unwrap().to_vec(),
+                            owner: *info.owner,
+                            executable: info.executable,
+                        });
+                    }
+                }
+            }
+            result
+        }
+        
+        /// Lay accounts out in the loader's input format, aligned for `deserialize`
+        fn serialize(&self, metas: &[AccountMeta], data: &[u8]) -> Vec<u64> {
+            let mut buffer = (metas.len() as u64).to_le_bytes().to_vec();
+            for (i, meta) in metas.iter().enumerate() {
+                if let Some(first) = metas[..i].iter().position(|other| other.pubkey == meta.pubkey) {
+                    buffer.push(first as u8);
+                    buffer.extend([0; 7]);
+                    continue;
+                }
+                
+                let account = self.accounts.get(&meta.pubkey).cloned().unwrap_or_default();
+                let same_key = metas.iter().filter(|other| other.pubkey == meta.pubkey);
+                let is_signer = same_key.clone().any(|other| other.is_signer);
+                let is_writable = same_key.clone().any(|other| other.is_writable);
+                
+                buffer.push(NON_DUP_MARKER);
+                buffer.push(is_signer as u8);
+                buffer.push(is_writable as u8);
+                buffer.push(account.executable as u8);
+                buffer.extend([0; 4]);
+                buffer.extend(meta.pubkey.as_ref());
+                buffer.extend(account.owner.as_ref());
+                buffer.extend(account.lamports.to_le_bytes());
+                buffer.extend((account.data.len() as u64).to_le_bytes());
+                buffer.extend(&account.data);
+                buffer.resize(buffer.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+                buffer.resize(buffer.len().next_multiple_of(8), 0);
+                buffer.extend(0u64.to_le_bytes());
+            }
+            buffer.extend((data.len() as u64).to_le_bytes());
+            buffer.extend(data);
+            buffer.extend(crate::ID.as_ref());
+            
+            let mut aligned = vec![0u64; buffer.len().div_ceil(8)];
+            unsafe {
+                std::ptr::copy_nonoverlapping(buffer.as_ptr(), aligned.as_mut_ptr() as *mut u8, buffer.len());
+            }
+            aligned
+        }
+    }
+    
+    fn staking_error(error: StakingError) -> ProgramError {
+        anchor_lang::error::Error::from(error).into()
+    }
+    
+    /// A pool paying rewards in the staked token, with its vault
+    struct TestPool {
+        authority: Pubkey,
+        mint: Pubkey,
+        global_state: Pubkey,
+        vault: Pubkey,
+        legacy: bool,  // Single pool created before pools were keyed, user accounts aren't scoped to it
+        streams: Vec<Pubkey>,  // Live reward streams, passed to instructions changing stake
+    }
+    
+    impl TestPool {
+        fn new(runtime: &mut TestRuntime, reward_rate: u64) -> Self {
+            let authority = runtime.wallet();
+            let mint = Pubkey::new_unique();
+            let (global_state_key, bump) = GlobalState::find_pda(&mint, 0);
+            let vault = Pubkey::new_unique();
+            runtime.set_token_account(vault, mint, global_state_key, 0);
+            
+            let mut global_state: GlobalState = zeroed(GlobalState::LEN);
+            global_state.version = GlobalState::VERSION;
+            global_state.authority = authority;
+            global_state.token_mint = mint;
+            global_state.vault = vault;
+            global_state.reward_mint = mint;
+            global_state.reward_vault = vault;
+            global_state.reward_rate = reward_rate;
+            global_state.unlock_duration = 7 * 86400;
+            global_state.early_unstake_penalty = 1000;
+            global_state.min_stake_amount = 1;
+            global_state.last_update_time = runtime.now();
+            global_state.parameter_change_delay = DEFAULT_PARAMETER_CHANGE_DELAY;
+            global_state.reward_index_updated_at = runtime.now();
+            global_state.bump = bump;
+            runtime.set_account(global_state_key, &global_state, GlobalState::LEN);
+            
+            Self {
+                authority,
+                mint,
+                global_state: global_state_key,
+                vault,
+                legacy: false,
+                streams: Vec::new(),
+            }
+        }
+        
+        fn user_info(&self, owner: &Pubkey) -> Pubkey {
+            let pool_seed: &[u8] = if self.legacy { &[] } else { self.global_state.as_ref() };
+            UserInfo::find_pda(pool_seed, owner).0
+        }
+        
+        /// Start a stream in `slot` emitting `reward_rate` for `duration` seconds from now,
+        /// with its vault holding the whole emission; returns the stream, vault and mint
+        fn add_stream(&mut self, runtime: &mut TestRuntime, slot: u8, reward_rate: u64, duration: i64) -> (Pubkey, Pubkey, Pubkey) {
+            let mut global_state: GlobalState = runtime.account(&self.global_state);
+            global_state.stream_count += 1;
+            global_state.live_streams |= 1 << slot;
+            
+            let (key, bump) = RewardStream::find_pda(&self.global_state, slot);
+            let mint = Pubkey::new_unique();
+            let vault = Pubkey::new_unique();
+            runtime.set_token_account(vault, mint, self.global_state, reward_rate * duration as u64);
+            
+            let mut stream: RewardStream = zeroed(RewardStream::LEN);
+            stream.pool = self.global_state;
+            stream.id = global_state.stream_count;
+            stream.slot = slot;
+            stream.mint = mint;
+            stream.vault = vault;
+            stream.reward_rate = reward_rate;
+            stream.start_time = runtime.now();
+            stream.end_time = runtime.now() + duration;
+            stream.last_update_time = runtime.now();
+            stream.bump = bump;
+            runtime.set_account(key, &stream, RewardStream::LEN);
+            runtime.set_account(self.global_state, &global_state, GlobalState::LEN);
+            
+            self.streams.push(key);
+            (key, vault, mint)
+        }
+        
+        fn stream_metas(&self) -> Vec<AccountMeta> {
+            self.streams.iter().map(|stream| AccountMeta::new(*stream, false)).collect()
+        }
+        
+        /// Register `owner`, with `payer` covering the rent
+        fn register(&self, runtime: &mut TestRuntime, owner: Pubkey, payer: Pubkey, referrer: Option<Pubkey>) {
+            runtime.process(
+                crate::accounts::RegisterUser {
+                    owner,
+                    payer,
+                    global_state: self.global_state,
+                    user_info: self.user_info(&owner),
+                    system_program: system_program::ID,
+                    rent: sysvar::rent::ID,
+                },
+                crate::instruction::RegisterUser { referrer },
+            ).unwrap();
+        }
+        
+        /// Token account for `owner` holding `amount` of the staked token
+        fn token_account(&self, runtime: &mut TestRuntime, owner: Pubkey, amount: u64) -> Pubkey {
+            let key = Pubkey::new_unique();
+            runtime.set_token_account(key, self.mint, owner, amount);
+            key
+        }
+        
+        fn stake(
+            &self,
+            runtime: &mut TestRuntime,
+            owner: Pubkey,
+            token_account: Pubkey,
+            referrer: Option<Pubkey>,
+            amount: u64,
+        ) -> ProgramResult {
+            runtime.process_with(
+                crate::accounts::Stake {
+                    owner,
+                    global_state: self.global_state,
+                    user_info: self.user_info(&owner),
+                    user_token_account: token_account,
+                    vault: self.vault,
+                    referrer_info: referrer.map(|referrer| self.user_info(&referrer)),
+                    token_program: spl_token::ID,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::Stake { amount },
+                self.stream_metas(),
+            )
+        }
+        
+        fn unstake(&self, runtime: &mut TestRuntime, owner: Pubkey, token_account: Pubkey, amount: u64) -> ProgramResult {
+            runtime.process_with(
+                crate::accounts::Unstake {
+                    owner,
+                    global_state: self.global_state,
+                    user_info: self.user_info(&owner),
+                    user_token_account: token_account,
+                    vault: self.vault,
+                    token_program: spl_token::ID,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::Unstake { amount },
+                self.stream_metas(),
+            )
+        }
+        
+        fn close_user(
+            &self,
+            runtime: &mut TestRuntime,
+            owner: Pubkey,
+            rent_receiver: Pubkey,
+            referrer: Option<Pubkey>,
+        ) -> ProgramResult {
+            runtime.process(
+                crate::accounts::CloseUser {
+                    owner,
+                    global_state: self.global_state,
+                    user_info: self.user_info(&owner),
+                    rent_receiver,
+                    referrer_info: referrer.map(|referrer| self.user_info(&referrer)),
+                },
+                crate::instruction::CloseUser {},
+            )
+        }
+    }
+    
+    #[test]
+    fn reward_carry_matches_single_settlement() {
+        let amount = 1_234_567;
+        let rate = 7;
+        let seconds = 100_000u128;
+        
+        let (once, _) = calculate_reward(amount, rate * seconds, 0).unwrap();
+        
+        let mut total = 0;
+        let mut remainder = 0;
+        for _ in 0..seconds {
+            let (reward, carry) = calculate_reward(amount, rate, remainder).unwrap();
+            total += reward;
+            remainder = carry;
+        }
+        
+        assert!(once > 0);
+        assert_eq!(total, once);
+    }
+    
+    #[test]
+    fn legacy_pool_keeps_single_pool_addresses() {
+        let mut global_state: GlobalState = zeroed(GlobalState::LEN);
+        global_state.token_mint = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        
+        let (keyed, _) = GlobalState::find_pda(&global_state.token_mint, 0);
+        assert_eq!(global_state.find_own_pda().0, keyed);
+        
+        global_state.legacy_seeds = true;
+        let (legacy, _) = GlobalState::find_legacy_pda();
+        assert_eq!(global_state.find_own_pda().0, legacy);
+        
+        let (user_pda, _) = UserInfo::find_pda(global_state.pool_seed(&legacy), &owner);
+        let (old_user_pda, _) =
+            Pubkey::find_program_address(&[b"user_info".as_ref(), owner.as_ref()], &crate::ID);
+        assert_eq!(user_pda, old_user_pda);
+    }
+    
+    #[test]
+    fn parameter_change_delay_has_a_minimum() {
+        let mut change = ParameterChange {
+            parameter_change_delay: Some(0),
+            ..Default::default()
+        };
+        assert!(change.validate().is_err());
+        change.parameter_change_delay = Some(MIN_PARAMETER_CHANGE_DELAY);
+        assert!(change.validate().is_ok());
+    }
+    
+    #[test]
+    fn legacy_user_keeps_the_rate_in_force() {
+        let mut global_state: GlobalState = zeroed(GlobalState::LEN);
+        global_state.reward_rate = 100;
+        global_state.accrue_rewards(1_000).unwrap();
+        
+        // Rate doubles before the legacy user upgrades
+        global_state.accrue_rewards(2_000).unwrap();
+        global_state.reward_rate = 200;
+        
+        let mut user_info: UserInfo = zeroed(UserInfo::LEN);
+        user_info.staked_amount = REWARD_INDEX_SCALE as u64;
+        user_info.last_stake_time = 400;
+        settle_rewards(&mut global_state, &mut user_info, 3_000).unwrap();
+        
+        // 1600s at the legacy rate, then 1000s at the new one
+        assert_eq!(user_info.rewards, 100 * 1_600 + 200 * 1_000);
+        assert_eq!(user_info.version, UserInfo::VERSION);
+    }
+    
+    #[test]
+    fn apr_is_simple_and_fits_the_deployed_rate() {
+        assert_eq!(calculate_apr(0), 0);
+        // The deploy script configures 1200 bps a day
+        assert_eq!(calculate_apr(1200), 438_000);
+        assert_eq!(calculate_apr(u64::MAX), u64::MAX);
+    }
+    
+    #[test]
+    fn reward_overflow_fails() {
+        assert!(calculate_reward(u64::MAX, u128::MAX, 0).is_err());
+        assert!(calculate_reward(1, u128::MAX - 1, REWARD_INDEX_SCALE).is_err());
+        // Fits in u128 but not in u64 once scaled down
+        assert!(calculate_reward(u64::MAX, REWARD_INDEX_SCALE * 2, 0).is_err());
+    }
+    
+    #[test]
+    fn penalty_rounds_up() {
+        assert_eq!(calculate_penalty(0, 5000).unwrap(), 0);
+        assert_eq!(calculate_penalty(1, 1).unwrap(), 1);
+        assert_eq!(calculate_penalty(3, 5000).unwrap(), 2);
+        assert_eq!(calculate_penalty(10_000, 100).unwrap(), 100);
+        assert!(calculate_penalty(u64::MAX, u64::MAX).is_err());
+    }
+    
+    #[test]
+    fn stream_emits_rate_only_while_staked() {
+        let mut stream: RewardStream = zeroed(RewardStream::LEN);
+        stream.reward_rate = 5;
+        stream.start_time = 100;
+        stream.end_time = 200;
+        
+        stream.accrue(0, 150).unwrap();
+        assert_eq!(stream.distributed, 0);
+        assert_eq!(stream.reward_index, 0);
+        
+        stream.accrue(10, 300).unwrap();
+        assert_eq!(stream.distributed, 250);
+        assert_eq!(stream.reward_index, 25 * STREAM_INDEX_SCALE);
+    }
+    
+    #[test]
+    fn campaign_emits_whole_budget() {
+        // One token unit over 30 days would round to a zero per-second rate
+        let mut stream: RewardStream = zeroed(RewardStream::LEN);
+        stream.budget = 1;
+        stream.start_time = 0;
+        stream.end_time = 30 * 86_400;
+        stream.accrue(1, stream.end_time).unwrap();
+        assert_eq!(stream.distributed, 1);
+        
+        // Piecewise accrual sums to the budget despite rounding each step
+        let mut stream: RewardStream = zeroed(RewardStream::LEN);
+        stream.budget = 1_000;
+        stream.start_time = 0;
+        stream.end_time = 7;
+        for time in 1..=10 {
+            stream.accrue(3, time).unwrap();
+        }
+        assert_eq!(stream.distributed, 1_000);
+    }
+    
+    #[test]
+    fn campaign_window_overflow_fails() {
+        let mut stream: RewardStream = zeroed(RewardStream::LEN);
+        stream.budget = 1;
+        stream.start_time = i64::MIN;
+        stream.end_time = i64::MAX;
+        assert!(stream.emitted_until(0).is_err());
+    }
+    
+    #[test]
+    fn drip_releases_linearly_and_completely() {
+        let mut global_state: GlobalState = zeroed(GlobalState::LEN);
+        global_state.undistributed_rewards = 10;
+        global_state.drip_updated_at = 0;
+        global_state.drip_end_time = 3;
+        
+        global_state.release_dripped_rewards(1).unwrap();
+        assert_eq!(global_state.reward_pool, 3);
+        global_state.release_dripped_rewards(2).unwrap();
+        assert_eq!(global_state.reward_pool, 6);
+        
+        // Releasing at an earlier time changes nothing
+        global_state.release_dripped_rewards(1).unwrap();
+        assert_eq!(global_state.reward_pool, 6);
+        
+        global_state.release_dripped_rewards(5).unwrap();
+        assert_eq!(global_state.reward_pool, 10);
+        assert_eq!(global_state.undistributed_rewards, 0);
+    }
+    
+    #[test]
+    fn close_user_refunds_payer_and_releases_referral() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let referrer = runtime.wallet();
+        let owner = runtime.wallet();
+        let relayer = runtime.wallet();
+        pool.register(&mut runtime, referrer, referrer, None);
+        pool.register(&mut runtime, owner, relayer, Some(referrer));
+        
+        let tokens = pool.token_account(&mut runtime, owner, 1_000);
+        pool.stake(&mut runtime, owner, tokens, Some(referrer), 1_000).unwrap();
+        let referrer_info: UserInfo = runtime.account(&pool.user_info(&referrer));
+        assert_eq!(referrer_info.referral_count, 1);
+        let global_state: GlobalState = runtime.account(&pool.global_state);
+        assert_eq!(global_state.stakers_count, 1);
+        
+        // A staked account can't be closed
+        assert_eq!(
+            pool.close_user(&mut runtime, owner, relayer, Some(referrer)),
+            Err(staking_error(StakingError::AccountNotEmpty))
+        );
+        
+        runtime.warp(7 * 86400);
+        pool.unstake(&mut runtime, owner, tokens, 1_000).unwrap();
+        assert_eq!(runtime.token_amount(&tokens), 1_000);
+        let global_state: GlobalState = runtime.account(&pool.global_state);
+        assert_eq!(global_state.stakers_count, 0);
+        
+        // Rent goes back to the relayer that paid it, not the owner
+        assert_eq!(
+            pool.close_user(&mut runtime, owner, owner, Some(referrer)),
+            Err(staking_error(StakingError::InvalidRentReceiver))
+        );
+        let user_rent = runtime.lamports(&pool.user_info(&owner));
+        let relayer_lamports = runtime.lamports(&relayer);
+        pool.close_user(&mut runtime, owner, relayer, Some(referrer)).unwrap();
+        
+        assert!(!runtime.exists(&pool.user_info(&owner)));
+        assert_eq!(runtime.lamports(&relayer), relayer_lamports + user_rent);
+        let referrer_info: UserInfo = runtime.account(&pool.user_info(&referrer));
+        assert_eq!(referrer_info.referral_count, 0);
+    }
+    
+    #[test]
+    fn close_user_requires_the_referrer_account() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let referrer = runtime.wallet();
+        let owner = runtime.wallet();
+        pool.register(&mut runtime, referrer, referrer, None);
+        pool.register(&mut runtime, owner, owner, Some(referrer));
+        
+        let tokens = pool.token_account(&mut runtime, owner, 10);
+        pool.stake(&mut runtime, owner, tokens, Some(referrer), 10).unwrap();
+        runtime.warp(7 * 86400);
+        pool.unstake(&mut runtime, owner, tokens, 10).unwrap();
+        
+        // Skipping the referrer would leave its referral count inflated
+        assert_eq!(
+            pool.close_user(&mut runtime, owner, owner, None),
+            Err(staking_error(StakingError::InvalidReferrer))
+        );
+        pool.close_user(&mut runtime, owner, owner, Some(referrer)).unwrap();
+    }
+    
+    #[test]
+    fn legacy_pool_and_user_migrate_in_place() {
+        let mut runtime = TestRuntime::new();
+        let authority = runtime.wallet();
+        let owner = runtime.wallet();
+        let mint = Pubkey::new_unique();
+        let (legacy_pda, canonical_bump) = GlobalState::find_legacy_pda();
+        let vault = Pubkey::new_unique();
+        runtime.set_token_account(vault, mint, legacy_pda, 1_000);
+        
+        let legacy_global_state = LegacyGlobalState {
+            authority,
+            token_mint: mint,
+            vault,
+            reward_rate: 0,
+            unlock_duration: 7 * 86400,
+            early_unstake_penalty: 1000,
+            min_stake_amount: 1,
+            referral_reward_rate: 500,
+            total_staked: 1_000,
+            stakers_count: 1,
+            reward_pool: 0,
+            last_update_time: runtime.now() - 30 * 86400,
+            bump: 255,
+        };
+        let mut data = GlobalState::DISCRIMINATOR.to_vec();
+        legacy_global_state.serialize(&mut data).unwrap();
+        assert_eq!(data.len(), LegacyGlobalState::LEN);
+        runtime.set_raw_account(legacy_pda, data);
+        
+        let (user_info, _) = UserInfo::find_pda(&[], &owner);
+        let legacy_user_info = LegacyUserInfo {
+            owner,
+            staked_amount: 1_000,
+            rewards: 0,
+            last_stake_time: runtime.now() - 10 * 86400,
+            last_claim_time: runtime.now() - 10 * 86400,
+            referrer: None,
+            referral_count: 0,
+            total_referral_rewards: 0,
+        };
+        let mut data = UserInfo::DISCRIMINATOR.to_vec();
+        legacy_user_info.serialize(&mut data).unwrap();
+        data.resize(LegacyUserInfo::LEN, 0);
+        runtime.set_raw_account(user_info, data);
+        
+        let migrate_global_state = |signer| crate::accounts::MigrateGlobalState {
+            authority: signer,
+            global_state: legacy_pda,
+            system_program: system_program::ID,
+        };
+        assert_eq!(
+            runtime.process(migrate_global_state(owner), crate::instruction::MigrateGlobalState {}),
+            Err(staking_error(StakingError::Unauthorized))
+        );
+        runtime.process(migrate_global_state(authority), crate::instruction::MigrateGlobalState {}).unwrap();
+        assert_eq!(
+            runtime.process(migrate_global_state(authority), crate::instruction::MigrateGlobalState {}),
+            Err(staking_error(StakingError::AlreadyMigrated))
+        );
+        
+        // Same address, grown to the current layout, with the canonical bump for signing
+        assert_eq!(runtime.accounts[&legacy_pda].data.len(), GlobalState::LEN);
+        let global_state: GlobalState = runtime.account(&legacy_pda);
+        assert_eq!(global_state.version, GlobalState::VERSION);
+        assert!(global_state.legacy_seeds);
+        assert_eq!(global_state.bump, canonical_bump);
+        assert_eq!(global_state.total_staked, 1_000);
+        assert_eq!(global_state.referral_reward_rate, 500);
+        assert_eq!(global_state.parameter_change_delay, DEFAULT_PARAMETER_CHANGE_DELAY);
+        
+        let payer = runtime.wallet();
+        runtime.process(
+            crate::accounts::MigrateUserInfo {
+                payer,
+                user_info,
+                global_state: legacy_pda,
+                system_program: system_program::ID,
+            },
+            crate::instruction::MigrateUserInfo {},
+        ).unwrap();
+        
+        assert_eq!(runtime.accounts[&user_info].data.len(), UserInfo::LEN);
+        let migrated: UserInfo = runtime.account(&user_info);
+        assert_eq!(migrated.version, UserInfo::VERSION);
+        assert_eq!(migrated.owner, owner);
+        assert_eq!(migrated.staked_amount, 1_000);
+        assert_eq!(migrated.lock_duration, 7 * 86400);
+        assert!(migrated.is_active);
+        
+        // The migrated position is usable through the legacy addresses
+        let pool = TestPool {
+            authority,
+            mint,
+            global_state: legacy_pda,
+            vault,
+            legacy: true,
+            streams: Vec::new(),
+        };
+        let tokens = pool.token_account(&mut runtime, owner, 0);
+        pool.unstake(&mut runtime, owner, tokens, 1_000).unwrap();
+        assert_eq!(runtime.token_amount(&tokens), 1_000);
+        let global_state: GlobalState = runtime.account(&legacy_pda);
+        assert_eq!(global_state.stakers_count, 0);
+        pool.close_user(&mut runtime, owner, owner, None).unwrap();
+    }
+    
+    /// Accounts shared by the council instructions
+    struct TestCouncil {
+        key: Pubkey,
+        global_state: Pubkey,
+    }
+    
+    impl TestCouncil {
+        fn new(pool: &TestPool) -> Self {
+            Self { key: AdminCouncil::find_pda(&pool.global_state).0, global_state: pool.global_state }
+        }
+        
+        fn initialize(&self, runtime: &mut TestRuntime, authority: Pubkey, members: Vec<Pubkey>, threshold: u8) -> ProgramResult {
+            runtime.process(
+                crate::accounts::InitializeCouncil {
+                    authority,
+                    global_state: self.global_state,
+                    council: self.key,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::InitializeCouncil { members, threshold },
+            )
+        }
+        
+        /// Create a proposal and return its address
+        fn propose(&self, runtime: &mut TestRuntime, proposer: Pubkey, action: ProposalAction) -> Pubkey {
+            let council: AdminCouncil = runtime.account(&self.key);
+            let (proposal, _) = Proposal::find_pda(&self.key, council.proposal_count);
+            runtime.process(
+                crate::accounts::CreateProposal {
+                    proposer,
+                    global_state: self.global_state,
+                    council: self.key,
+                    proposal,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::CreateProposal { action },
+            ).unwrap();
+            proposal
+        }
+        
+        fn approve(&self, runtime: &mut TestRuntime, member: Pubkey, proposal: Pubkey) -> ProgramResult {
+            runtime.process(
+                crate::accounts::ApproveProposal {
+                    member,
+                    global_state: self.global_state,
+                    council: self.key,
+                    proposal,
+                },
+                crate::instruction::ApproveProposal {},
+            )
+        }
+        
+        fn execute_council(&self, runtime: &mut TestRuntime, executor: Pubkey, proposal: Pubkey) -> ProgramResult {
+            runtime.process(
+                crate::accounts::ExecuteCouncilProposal {
+                    executor,
+                    global_state: self.global_state,
+                    council: self.key,
+                    proposal,
+                },
+                crate::instruction::ExecuteCouncilProposal {},
+            )
+        }
+    }
+    
+    #[test]
+    fn council_queues_and_cancels_parameter_changes() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let council = TestCouncil::new(&pool);
+        let members = [runtime.wallet(), runtime.wallet(), runtime.wallet()];
+        council.initialize(&mut runtime, pool.authority, members.to_vec(), 2).unwrap();
+        let (pending_change, _) = PendingParameterChange::find_pda(&pool.global_state);
+        
+        // The authority can no longer queue or cancel changes on its own
+        assert_eq!(
+            runtime.process(
+                crate::accounts::UpdateParameters {
+                    authority: pool.authority,
+                    global_state: pool.global_state,
+                    pending_change,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::UpdateParameters {
+                    reward_rate: Some(5),
+                    unlock_duration: None,
+                    early_unstake_penalty: None,
+                    min_stake_amount: None,
+                    referral_reward_rate: None,
+                    parameter_change_delay: None,
+                },
+            ),
+            Err(staking_error(StakingError::CouncilApprovalRequired))
+        );
+        
+        let change = ParameterChange { reward_rate: Some(5), ..ParameterChange::default() };
+        let proposal = council.propose(&mut runtime, members[0], ProposalAction::UpdateParameters(change));
+        let execute = |runtime: &mut TestRuntime, executor| runtime.process(
+            crate::accounts::ExecuteProposal {
+                executor,
+                global_state: pool.global_state,
+                council: council.key,
+                proposal,
+                pending_change,
+                system_program: system_program::ID,
+            },
+            crate::instruction::ExecuteProposal {},
+        );
+        let executor = runtime.wallet();
+        assert_eq!(execute(&mut runtime, executor), Err(staking_error(StakingError::InsufficientApprovals)));
+        council.approve(&mut runtime, members[1], proposal).unwrap();
+        assert_eq!(
+            council.approve(&mut runtime, members[1], proposal),
+            Err(staking_error(StakingError::AlreadyApproved))
+        );
+        execute(&mut runtime, executor).unwrap();
+        
+        let pending: PendingParameterChange = runtime.account(&pending_change);
+        assert_eq!(pending.change.reward_rate, Some(5));
+        assert_eq!(pending.queued_by, executor);
+        assert_eq!(pending.effective_time, runtime.now() + DEFAULT_PARAMETER_CHANGE_DELAY);
+        assert_eq!(execute(&mut runtime, executor), Err(staking_error(StakingError::ProposalAlreadyExecuted)));
+        
+        // Only a council proposal can veto the queued change
+        assert_eq!(
+            runtime.process(
+                crate::accounts::CancelPendingParameters {
+                    authority: pool.authority,
+                    global_state: pool.global_state,
+                    pending_change,
+                    rent_receiver: executor,
+                },
+                crate::instruction::CancelPendingParameters {},
+            ),
+            Err(staking_error(StakingError::CouncilApprovalRequired))
+        );
+        let cancel = council.propose(&mut runtime, members[2], ProposalAction::CancelPendingParameters);
+        council.approve(&mut runtime, members[0], cancel).unwrap();
+        runtime.process(
+            crate::accounts::ExecuteCancelProposal {
+                executor: members[0],
+                global_state: pool.global_state,
+                council: council.key,
+                proposal: cancel,
+                pending_change,
+                rent_receiver: executor,
+            },
+            crate::instruction::ExecuteCancelProposal {},
+        ).unwrap();
+        assert!(!runtime.exists(&pending_change));
+    }
+    
+    #[test]
+    fn council_rotates_disables_and_reenables() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let council = TestCouncil::new(&pool);
+        let members = [runtime.wallet(), runtime.wallet(), runtime.wallet(), runtime.wallet()];
+        council.initialize(&mut runtime, pool.authority, members[..3].to_vec(), 2).unwrap();
+        
+        // Rotate the first member out for the fourth
+        let rotate = council.propose(&mut runtime, members[0], ProposalAction::UpdateCouncil {
+            members: members[1..].to_vec(),
+            threshold: 2,
+        });
+        assert_eq!(
+            council.execute_council(&mut runtime, members[0], rotate),
+            Err(staking_error(StakingError::InsufficientApprovals))
+        );
+        council.approve(&mut runtime, members[1], rotate).unwrap();
+        council.execute_council(&mut runtime, members[0], rotate).unwrap();
+        
+        let state: AdminCouncil = runtime.account(&council.key);
+        assert_eq!(state.members, members[1..].to_vec());
+        
+        // The rotated out member's approval no longer counts towards the threshold
+        let change = ParameterChange { reward_rate: Some(5), ..ParameterChange::default() };
+        let left_over = council.propose(&mut runtime, members[1], ProposalAction::UpdateParameters(change));
+        assert_eq!(
+            council.approve(&mut runtime, members[0], left_over),
+            Err(staking_error(StakingError::NotCouncilMember))
+        );
+        
+        let disable = council.propose(&mut runtime, members[3], ProposalAction::DisableCouncil);
+        council.approve(&mut runtime, members[2], disable).unwrap();
+        council.execute_council(&mut runtime, members[3], disable).unwrap();
+        let global_state: GlobalState = runtime.account(&pool.global_state);
+        assert!(!global_state.council_enabled);
+        assert_eq!(
+            council.approve(&mut runtime, members[2], left_over),
+            Err(staking_error(StakingError::CouncilNotEnabled))
+        );
+        
+        // Re-enabling keeps the proposal numbering, but proposals from before are stale
+        council.initialize(&mut runtime, pool.authority, members[1..].to_vec(), 2).unwrap();
+        assert_eq!(
+            council.initialize(&mut runtime, pool.authority, members[1..].to_vec(), 2),
+            Err(staking_error(StakingError::InvalidCouncil))
+        );
+        let state: AdminCouncil = runtime.account(&council.key);
+        assert_eq!(state.epoch, 2);
+        assert_eq!(state.proposal_count, 3);
+        assert_eq!(
+            council.approve(&mut runtime, members[2], left_over),
+            Err(staking_error(StakingError::StaleProposal))
+        );
+    }
+    
+    #[test]
+    fn gift_lock_holds_only_gifted_stake() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let owner = runtime.wallet();
+        let gifter = runtime.wallet();
+        pool.register(&mut runtime, owner, owner, None);
+        let tokens = pool.token_account(&mut runtime, owner, 100);
+        let gifter_tokens = pool.token_account(&mut runtime, gifter, 1_000);
+        let stake_for = |runtime: &mut TestRuntime, amount, lock| runtime.process(
+            crate::accounts::StakeFor {
+                payer: gifter,
+                beneficiary: owner,
+                global_state: pool.global_state,
+                user_info: pool.user_info(&owner),
+                payer_token_account: gifter_tokens,
+                vault: pool.vault,
+                referrer_info: None,
+                token_program: spl_token::ID,
+                system_program: system_program::ID,
+            },
+            crate::instruction::StakeFor { amount, lock },
+        );
+        
+        pool.stake(&mut runtime, owner, tokens, None, 100).unwrap();
+        runtime.warp(7 * 86400 + 1);
+        let gifted_at = runtime.now();
+        stake_for(&mut runtime, 500, true).unwrap();
+        
+        // The gift is locked on its own clock, the owner's stake stays unlocked
+        let user_info: UserInfo = runtime.account(&pool.user_info(&owner));
+        assert_eq!(user_info.staked_amount, 600);
+        assert_eq!(user_info.locked_amount, 500);
+        assert_eq!(user_info.gift_unlock_time, gifted_at + 7 * 86400);
+        assert_eq!(user_info.last_stake_time, gifted_at - 7 * 86400 - 1);
+        assert_eq!(
+            pool.unstake(&mut runtime, owner, tokens, 101),
+            Err(staking_error(StakingError::StakeLocked))
+        );
+        pool.unstake(&mut runtime, owner, tokens, 100).unwrap();
+        assert_eq!(runtime.token_amount(&tokens), 100);
+        
+        // A later gift joins the running lock instead of extending it
+        runtime.warp(3 * 86400);
+        stake_for(&mut runtime, 200, true).unwrap();
+        let user_info: UserInfo = runtime.account(&pool.user_info(&owner));
+        assert_eq!(user_info.locked_amount, 700);
+        assert_eq!(user_info.gift_unlock_time, gifted_at + 7 * 86400);
+        
+        runtime.warp(4 * 86400);
+        pool.unstake(&mut runtime, owner, tokens, 700).unwrap();
+        assert_eq!(runtime.token_amount(&tokens), 800);
+        assert_eq!(runtime.token_amount(&gifter_tokens), 300);
+    }
+    
+    #[test]
+    fn closed_stream_forfeits_rewards_left_in_it() {
+        let mut runtime = TestRuntime::new();
+        let mut pool = TestPool::new(&mut runtime, 0);
+        let owner = runtime.wallet();
+        let other = runtime.wallet();
+        pool.register(&mut runtime, owner, owner, None);
+        pool.register(&mut runtime, other, other, None);
+        let owner_tokens = pool.token_account(&mut runtime, owner, 100);
+        let other_tokens = pool.token_account(&mut runtime, other, 100);
+        
+        let (stream, stream_vault, stream_mint) = pool.add_stream(&mut runtime, 0, 10, 100);
+        pool.stake(&mut runtime, owner, owner_tokens, None, 100).unwrap();
+        pool.stake(&mut runtime, other, other_tokens, None, 100).unwrap();
+        runtime.warp(7 * 86400);
+        pool.unstake(&mut runtime, owner, owner_tokens, 100).unwrap();
+        
+        // Unclaimed rewards in a live stream keep the account open
+        let user_info: UserInfo = runtime.account(&pool.user_info(&owner));
+        assert_eq!(user_info.stream_rewards[0].rewards, 500);
+        assert_eq!(
+            pool.close_user(&mut runtime, owner, owner, None),
+            Err(staking_error(StakingError::AccountNotEmpty))
+        );
+        
+        runtime.warp(REWARD_STREAM_CLAIM_WINDOW);
+        let destination = Pubkey::new_unique();
+        runtime.set_token_account(destination, stream_mint, pool.authority, 0);
+        runtime.process(
+            crate::accounts::CloseRewardStream {
+                authority: pool.authority,
+                global_state: pool.global_state,
+                stream,
+                stream_vault,
+                destination,
+                token_program: spl_token::ID,
+            },
+            crate::instruction::CloseRewardStream {},
+        ).unwrap();
+        pool.streams.clear();
+        assert_eq!(runtime.token_amount(&destination), 1_000);
+        assert!(!runtime.exists(&stream) && !runtime.exists(&stream_vault));
+        let global_state: GlobalState = runtime.account(&pool.global_state);
+        assert_eq!(global_state.live_streams, 0);
+        assert_eq!(global_state.closed_stream_ids[0], 1);
+        
+        // Once the stream is closed its rewards are forfeited and no longer block the close
+        pool.close_user(&mut runtime, owner, owner, None).unwrap();
+        assert!(!runtime.exists(&pool.user_info(&owner)));
+        
+        // A new stream in the slot drops what is left of the closed one
+        pool.add_stream(&mut runtime, 0, 10, 100);
+        pool.unstake(&mut runtime, other, other_tokens, 100).unwrap();
+        let user_info: UserInfo = runtime.account(&pool.user_info(&other));
+        assert_eq!(user_info.stream_rewards[0].stream_id, 2);
+        assert_eq!(user_info.stream_rewards[0].rewards, 0);
+    }
+    
+    #[test]
+    fn pause_bits_gate_their_own_instructions() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let owner = runtime.wallet();
+        pool.register(&mut runtime, owner, owner, None);
+        let tokens = pool.token_account(&mut runtime, owner, 100);
+        pool.stake(&mut runtime, owner, tokens, None, 50).unwrap();
+        runtime.warp(7 * 86400);
+        
+        let set_pause = |runtime: &mut TestRuntime, authority, paused| runtime.process(
+            crate::accounts::SetPause { authority, global_state: pool.global_state },
+            crate::instruction::SetPause { paused },
+        );
+        assert_eq!(
+            set_pause(&mut runtime, owner, PAUSE_STAKE),
+            Err(staking_error(StakingError::Unauthorized))
+        );
+        assert_eq!(
+            set_pause(&mut runtime, pool.authority, 1 << 5),
+            Err(staking_error(StakingError::InvalidParameter))
+        );
+        
+        // Pausing stakes leaves the exit open
+        set_pause(&mut runtime, pool.authority, PAUSE_STAKE | PAUSE_REGISTER).unwrap();
+        assert_eq!(
+            pool.stake(&mut runtime, owner, tokens, None, 50),
+            Err(staking_error(StakingError::Paused))
+        );
+        let newcomer = runtime.wallet();
+        assert_eq!(
+            runtime.process(
+                crate::accounts::RegisterUser {
+                    owner: newcomer,
+                    payer: newcomer,
+                    global_state: pool.global_state,
+                    user_info: pool.user_info(&newcomer),
+                    system_program: system_program::ID,
+                    rent: sysvar::rent::ID,
+                },
+                crate::instruction::RegisterUser { referrer: None },
+            ),
+            Err(staking_error(StakingError::Paused))
+        );
+        pool.unstake(&mut runtime, owner, tokens, 10).unwrap();
+        
+        set_pause(&mut runtime, pool.authority, PAUSE_UNSTAKE).unwrap();
+        assert_eq!(
+            pool.unstake(&mut runtime, owner, tokens, 10),
+            Err(staking_error(StakingError::Paused))
+        );
+        pool.stake(&mut runtime, owner, tokens, None, 10).unwrap();
+        
+        set_pause(&mut runtime, pool.authority, 0).unwrap();
+        runtime.warp(7 * 86400);
+        pool.unstake(&mut runtime, owner, tokens, 50).unwrap();
+        assert_eq!(runtime.token_amount(&tokens), 100);
+    }
+    
+    #[test]
+    fn register_and_stake_registers_once() {
+        let mut runtime = TestRuntime::new();
+        let pool = TestPool::new(&mut runtime, 0);
+        let referrer = runtime.wallet();
+        let owner = runtime.wallet();
+        let relayer = runtime.wallet();
+        pool.register(&mut runtime, referrer, referrer, None);
+        let tokens = pool.token_account(&mut runtime, owner, 100);
+        let register_and_stake = |runtime: &mut TestRuntime, referrer: Option<Pubkey>, amount| runtime.process(
+            crate::accounts::RegisterAndStake {
+                owner,
+                payer: relayer,
+                global_state: pool.global_state,
+                user_info: pool.user_info(&owner),
+                user_token_account: tokens,
+                vault: pool.vault,
+                referrer_info: referrer.map(|referrer| pool.user_info(&referrer)),
+                token_program: spl_token::ID,
+                system_program: system_program::ID,
+            },
+            crate::instruction::RegisterAndStake { referrer, amount },
+        );
+        
+        register_and_stake(&mut runtime, Some(referrer), 60).unwrap();
+        let user_info: UserInfo = runtime.account(&pool.user_info(&owner));
+        assert_eq!(user_info.owner, owner);
+        assert_eq!(user_info.referrer, Some(referrer));
+        assert_eq!(user_info.rent_payer, relayer);
+        assert_eq!(user_info.staked_amount, 60);
+        let referrer_info: UserInfo = runtime.account(&pool.user_info(&referrer));
+        assert_eq!(referrer_info.referral_count, 1);
+        
+        // Later calls keep the recorded referrer and don't count the referral again
+        let other = runtime.wallet();
+        pool.register(&mut runtime, other, other, None);
+        assert_eq!(
+            register_and_stake(&mut runtime, Some(other), 10),
+            Err(staking_error(StakingError::InvalidReferrer))
+        );
+        register_and_stake(&mut runtime, None, 40).unwrap();
+        let user_info: UserInfo = runtime.account(&pool.user_info(&owner));
+        assert_eq!(user_info.staked_amount, 100);
+        let referrer_info: UserInfo = runtime.account(&pool.user_info(&referrer));
+        assert_eq!(referrer_info.referral_count, 1);
+        let global_state: GlobalState = runtime.account(&pool.global_state);
+        assert_eq!(global_state.stakers_count, 1);
+        assert_eq!(runtime.token_amount(&pool.vault), 100);
+    }
+}
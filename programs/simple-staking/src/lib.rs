@@ -17,6 +17,13 @@ pub mod simple_staking {
         vault.bump = ctx.bumps.vault;
         vault.vault_bump = ctx.bumps.vault_authority;
         
+        emit!(Initialized {
+            authority: vault.authority,
+            token_mint: vault.token_mint,
+            token_vault: vault.token_vault,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+        
         msg!("Staking vault initialized");
         Ok(())
     }
@@ -32,6 +39,11 @@ pub mod simple_staking {
         user_info.last_claim_timestamp = Clock::get()?.unix_timestamp;
         user_info.bump = ctx.bumps.user_info;
         
+        emit!(Registered {
+            owner: user_info.owner,
+            timestamp: user_info.last_stake_timestamp,
+        });
+        
         msg!("User registered for staking");
         Ok(())
     }
@@ -64,6 +76,13 @@ pub mod simple_staking {
         user_info.amount_staked = user_info.amount_staked.checked_add(amount).unwrap();
         user_info.last_stake_timestamp = Clock::get()?.unix_timestamp;
 
+        emit!(Staked {
+            owner: user.key(),
+            amount,
+            amount_staked: user_info.amount_staked,
+            timestamp: user_info.last_stake_timestamp,
+        });
+
         msg!("Staked {} tokens", amount);
         Ok(())
     }
@@ -106,9 +125,35 @@ pub mod simple_staking {
         // Update user staking info
         user_info.amount_staked = user_info.amount_staked.checked_sub(amount).unwrap();
 
+        emit!(Unstaked {
+            owner: ctx.accounts.user.key(),
+            amount,
+            amount_staked: user_info.amount_staked,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
         msg!("Unstaked {} tokens", amount);
         Ok(())
     }
+
+    /// Close an empty user account and return its rent
+    pub fn close_user(ctx: Context<CloseUser>) -> Result<()> {
+        let user_info = &ctx.accounts.user_info;
+
+        // Only empty accounts can be closed
+        require!(
+            user_info.amount_staked == 0 && user_info.rewards_earned == 0,
+            ErrorCode::AccountNotEmpty
+        );
+
+        emit!(UserClosed {
+            owner: user_info.owner,
+            timestamp: Clock::get()?.unix_timestamp,
+        });
+
+        msg!("User account closed");
+        Ok(())
+    }
 }
 
 #[account]
@@ -159,6 +204,17 @@ pub struct Initialize<'info> {
     )]
     pub token_vault: Account<'info, TokenAccount>,
     
+    #[account(
+        constraint = program.programdata_address()? == Some(program_data.key()) @ ErrorCode::Unauthorized,
+    )]
+    pub program: Program<'info, crate::program::SimpleStaking>,
+    
+    /// Only the program's upgrade authority can initialize the vault
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(authority.key()) @ ErrorCode::Unauthorized,
+    )]
+    pub program_data: Account<'info, ProgramData>,
+    
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -269,8 +325,65 @@ pub struct Unstake<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct CloseUser<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    
+    #[account(
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump = user_info.bump,
+        constraint = user_info.owner == user.key(),
+        close = user
+    )]
+    pub user_info: Account<'info, UserStakeInfo>,
+    
+    pub system_program: Program<'info, System>,
+}
+
+#[event]
+pub struct Initialized {
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub token_vault: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Registered {
+    pub owner: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Staked {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub amount_staked: u64,             // User's stake after this deposit
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct Unstaked {
+    pub owner: Pubkey,
+    pub amount: u64,                    // No early unstake penalty in this program
+    pub amount_staked: u64,             // User's stake after this withdrawal
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UserClosed {
+    pub owner: Pubkey,
+    pub timestamp: i64,
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Insufficient staked tokens")]
     InsufficientStake,
+    #[msg("Signer is not the program upgrade authority")]
+    Unauthorized,
+    #[msg("Account still holds staked tokens or rewards")]
+    AccountNotEmpty,
 }
\ No newline at end of file